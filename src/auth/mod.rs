@@ -1,2 +1,3 @@
 pub mod mtls;
 pub mod shared_secret;
+pub mod signer;
@@ -0,0 +1,569 @@
+use rustls::client::ResolvesClientCert;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::sign::{CertifiedKey, Signer, SigningKey};
+use rustls::{SignatureAlgorithm, SignatureScheme};
+use std::fmt::Debug;
+use std::io::{Read, Write};
+use std::process::{Child, Command, Stdio};
+use std::sync::{mpsc, Arc};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tokio::runtime::RuntimeFlavor;
+use x509_parser::oid_registry::{
+    OID_EC_P256, OID_KEY_TYPE_EC_PUBLIC_KEY, OID_NIST_EC_P384, OID_PKCS1_RSAENCRYPTION,
+    OID_SIG_ED25519,
+};
+
+#[derive(Debug, Error)]
+pub enum SignerError {
+    #[error("unsupported key: {0}")]
+    UnsupportedKey(String),
+    #[error("unsupported signature scheme: {0:?}")]
+    UnsupportedScheme(SignatureScheme),
+    #[error("sign command failed: {0}")]
+    Command(String),
+}
+
+/// Produces TLS handshake signatures with the device private key.
+///
+/// The key itself can live anywhere (PEM file, secure element, HSM);
+/// implementations only have to sign on request.
+pub trait DeviceKeySigner: Debug + Send + Sync {
+    /// Kind of key this signer holds.
+    fn algorithm(&self) -> SignatureAlgorithm;
+
+    /// Signature schemes this signer can produce, most preferred first.
+    fn schemes(&self) -> Vec<SignatureScheme>;
+
+    /// Sign `message` with `scheme`. The message is not hashed; the signer
+    /// must hash it with the digest implied by the scheme.
+    fn sign(&self, scheme: SignatureScheme, message: &[u8]) -> Result<Vec<u8>, SignerError>;
+}
+
+/// Public key type of a device certificate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyType {
+    EcdsaP256,
+    EcdsaP384,
+    Rsa,
+    Ed25519,
+}
+
+impl KeyType {
+    /// Detect the key type from the certificate's SubjectPublicKeyInfo.
+    pub fn from_cert(cert: &CertificateDer<'_>) -> Result<Self, SignerError> {
+        let (_, cert) = x509_parser::parse_x509_certificate(cert)
+            .map_err(|e| SignerError::UnsupportedKey(e.to_string()))?;
+        let spki = &cert.public_key().algorithm;
+
+        if spki.algorithm == OID_PKCS1_RSAENCRYPTION {
+            return Ok(KeyType::Rsa);
+        }
+        if spki.algorithm == OID_SIG_ED25519 {
+            return Ok(KeyType::Ed25519);
+        }
+        if spki.algorithm == OID_KEY_TYPE_EC_PUBLIC_KEY {
+            let curve = spki.parameters.as_ref().and_then(|p| p.as_oid().ok());
+            return match curve {
+                Some(oid) if oid == OID_EC_P256 => Ok(KeyType::EcdsaP256),
+                Some(oid) if oid == OID_NIST_EC_P384 => Ok(KeyType::EcdsaP384),
+                other => Err(SignerError::UnsupportedKey(format!(
+                    "unsupported EC curve {:?}",
+                    other.map(|oid| oid.to_id_string())
+                ))),
+            };
+        }
+        Err(SignerError::UnsupportedKey(format!(
+            "unsupported key algorithm {}",
+            spki.algorithm.to_id_string()
+        )))
+    }
+
+    pub fn algorithm(&self) -> SignatureAlgorithm {
+        match self {
+            KeyType::EcdsaP256 | KeyType::EcdsaP384 => SignatureAlgorithm::ECDSA,
+            KeyType::Rsa => SignatureAlgorithm::RSA,
+            KeyType::Ed25519 => SignatureAlgorithm::ED25519,
+        }
+    }
+
+    pub fn schemes(&self) -> Vec<SignatureScheme> {
+        match self {
+            KeyType::EcdsaP256 => vec![SignatureScheme::ECDSA_NISTP256_SHA256],
+            KeyType::EcdsaP384 => vec![SignatureScheme::ECDSA_NISTP384_SHA384],
+            KeyType::Rsa => vec![
+                SignatureScheme::RSA_PSS_SHA256,
+                SignatureScheme::RSA_PSS_SHA384,
+                SignatureScheme::RSA_PSS_SHA512,
+                SignatureScheme::RSA_PKCS1_SHA256,
+                SignatureScheme::RSA_PKCS1_SHA384,
+                SignatureScheme::RSA_PKCS1_SHA512,
+            ],
+            KeyType::Ed25519 => vec![SignatureScheme::ED25519],
+        }
+    }
+}
+
+/// Name of a signature scheme as passed to a sign command (TLS 1.3 registry names).
+pub fn scheme_name(scheme: SignatureScheme) -> Option<&'static str> {
+    match scheme {
+        SignatureScheme::ECDSA_NISTP256_SHA256 => Some("ecdsa_secp256r1_sha256"),
+        SignatureScheme::ECDSA_NISTP384_SHA384 => Some("ecdsa_secp384r1_sha384"),
+        SignatureScheme::RSA_PSS_SHA256 => Some("rsa_pss_rsae_sha256"),
+        SignatureScheme::RSA_PSS_SHA384 => Some("rsa_pss_rsae_sha384"),
+        SignatureScheme::RSA_PSS_SHA512 => Some("rsa_pss_rsae_sha512"),
+        SignatureScheme::RSA_PKCS1_SHA256 => Some("rsa_pkcs1_sha256"),
+        SignatureScheme::RSA_PKCS1_SHA384 => Some("rsa_pkcs1_sha384"),
+        SignatureScheme::RSA_PKCS1_SHA512 => Some("rsa_pkcs1_sha512"),
+        SignatureScheme::ED25519 => Some("ed25519"),
+        _ => None,
+    }
+}
+
+/// Signs with a private key held in memory (loaded from a key file).
+#[derive(Debug)]
+pub struct PemKeySigner {
+    key: Arc<dyn SigningKey>,
+    schemes: Vec<SignatureScheme>,
+}
+
+impl PemKeySigner {
+    pub fn new(key: &PrivateKeyDer<'_>) -> Result<Self, SignerError> {
+        let key = rustls::crypto::ring::sign::any_supported_type(key)
+            .map_err(|e| SignerError::UnsupportedKey(e.to_string()))?;
+        let schemes = [KeyType::EcdsaP256, KeyType::EcdsaP384, KeyType::Rsa, KeyType::Ed25519]
+            .iter()
+            .flat_map(|t| t.schemes())
+            .filter(|scheme| key.choose_scheme(&[*scheme]).is_some())
+            .collect();
+        Ok(Self { key, schemes })
+    }
+}
+
+impl DeviceKeySigner for PemKeySigner {
+    fn algorithm(&self) -> SignatureAlgorithm {
+        self.key.algorithm()
+    }
+
+    fn schemes(&self) -> Vec<SignatureScheme> {
+        self.schemes.clone()
+    }
+
+    fn sign(&self, scheme: SignatureScheme, message: &[u8]) -> Result<Vec<u8>, SignerError> {
+        let signer = self
+            .key
+            .choose_scheme(&[scheme])
+            .ok_or(SignerError::UnsupportedScheme(scheme))?;
+        signer
+            .sign(message)
+            .map_err(|e| SignerError::UnsupportedKey(e.to_string()))
+    }
+}
+
+/// Signs by running an external command, for keys that never leave a
+/// secure element or HSM.
+///
+/// Protocol: the command runs via `sh -c` with `HUB_LINK_SIGN_SCHEME` set to
+/// the scheme name (e.g. `ecdsa_secp256r1_sha256`). The unhashed message is
+/// written to stdin and the raw signature is read from stdout (DER for ECDSA).
+/// A non-zero exit status fails the handshake, and so does a command that
+/// runs longer than [`SIGN_TIMEOUT`]; it is killed.
+///
+/// The handshake calls this on a runtime worker thread. On a multi-thread
+/// runtime the worker's other tasks move to another thread while the command
+/// runs, so keepalive and the control socket aren't stalled.
+#[derive(Debug)]
+pub struct CommandSigner {
+    command: String,
+    key_type: KeyType,
+    timeout: Duration,
+}
+
+/// How long a sign command may run. The handshake blocks meanwhile, so
+/// this stays well below the default 10 second pong timeout.
+const SIGN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often a running sign command is checked for exit.
+const SIGN_POLL: Duration = Duration::from_millis(5);
+
+impl CommandSigner {
+    pub fn new(command: String, key_type: KeyType) -> Self {
+        Self {
+            command,
+            key_type,
+            timeout: SIGN_TIMEOUT,
+        }
+    }
+
+    fn run(&self, name: &str, message: &[u8]) -> Result<Vec<u8>, SignerError> {
+        let deadline = Instant::now() + self.timeout;
+
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(&self.command)
+            .env("HUB_LINK_SIGN_SCHEME", name)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| SignerError::Command(e.to_string()))?;
+        // Read in the background so a chatty command can't fill a pipe
+        let stdout = read_pipe(child.stdout.take().expect("stdout is piped"));
+        let stderr = read_pipe(child.stderr.take().expect("stderr is piped"));
+
+        // Dropping stdin closes it so the command sees EOF. A command that
+        // exits without reading it is reported by its exit status below.
+        if let Err(e) = child
+            .stdin
+            .take()
+            .expect("stdin is piped")
+            .write_all(message)
+        {
+            if e.kind() != std::io::ErrorKind::BrokenPipe {
+                reap(&mut child);
+                return Err(SignerError::Command(e.to_string()));
+            }
+        }
+
+        let status = loop {
+            match child.try_wait() {
+                Ok(Some(status)) => break status,
+                Ok(None) if Instant::now() < deadline => std::thread::sleep(SIGN_POLL),
+                Ok(None) => {
+                    reap(&mut child);
+                    return Err(SignerError::Command(format!(
+                        "timed out after {:?}",
+                        self.timeout
+                    )));
+                }
+                Err(e) => {
+                    reap(&mut child);
+                    return Err(SignerError::Command(e.to_string()));
+                }
+            }
+        };
+        let stdout = collect_pipe(stdout, deadline)?;
+        if !status.success() {
+            let stderr = collect_pipe(stderr, deadline).unwrap_or_default();
+            return Err(SignerError::Command(format!(
+                "exit code {}: {}",
+                status,
+                String::from_utf8_lossy(&stderr).trim()
+            )));
+        }
+        if stdout.is_empty() {
+            return Err(SignerError::Command(
+                "command produced empty signature".to_string(),
+            ));
+        }
+        Ok(stdout)
+    }
+}
+
+impl DeviceKeySigner for CommandSigner {
+    fn algorithm(&self) -> SignatureAlgorithm {
+        self.key_type.algorithm()
+    }
+
+    fn schemes(&self) -> Vec<SignatureScheme> {
+        self.key_type.schemes()
+    }
+
+    fn sign(&self, scheme: SignatureScheme, message: &[u8]) -> Result<Vec<u8>, SignerError> {
+        let name = scheme_name(scheme).ok_or(SignerError::UnsupportedScheme(scheme))?;
+        match tokio::runtime::Handle::try_current() {
+            Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => {
+                tokio::task::block_in_place(|| self.run(name, message))
+            }
+            _ => self.run(name, message),
+        }
+    }
+}
+
+/// Kill a sign command and wait for it, so it doesn't linger as a zombie.
+fn reap(child: &mut Child) {
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+/// Read a child's pipe to the end on its own thread.
+fn read_pipe<R: Read + Send + 'static>(mut pipe: R) -> mpsc::Receiver<Vec<u8>> {
+    let (tx, rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        let mut data = Vec::new();
+        let _ = pipe.read_to_end(&mut data);
+        let _ = tx.send(data);
+    });
+    rx
+}
+
+/// Wait for a pipe read by [`read_pipe`]. A background process the command
+/// started may hold the pipe open, so this gives up at `deadline` too.
+fn collect_pipe(rx: mpsc::Receiver<Vec<u8>>, deadline: Instant) -> Result<Vec<u8>, SignerError> {
+    rx.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+        .map_err(|_| SignerError::Command("output not closed before the timeout".to_string()))
+}
+
+/// Adapts a [`DeviceKeySigner`] to rustls' [`SigningKey`].
+#[derive(Debug)]
+struct SignerKey(Arc<dyn DeviceKeySigner>);
+
+impl SigningKey for SignerKey {
+    fn choose_scheme(&self, offered: &[SignatureScheme]) -> Option<Box<dyn Signer>> {
+        let scheme = self
+            .0
+            .schemes()
+            .into_iter()
+            .find(|scheme| offered.contains(scheme))?;
+        Some(Box::new(SchemeSigner {
+            signer: self.0.clone(),
+            scheme,
+        }))
+    }
+
+    fn algorithm(&self) -> SignatureAlgorithm {
+        self.0.algorithm()
+    }
+}
+
+#[derive(Debug)]
+struct SchemeSigner {
+    signer: Arc<dyn DeviceKeySigner>,
+    scheme: SignatureScheme,
+}
+
+impl Signer for SchemeSigner {
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>, rustls::Error> {
+        self.signer
+            .sign(self.scheme, message)
+            .map_err(|e| rustls::Error::General(e.to_string()))
+    }
+
+    fn scheme(&self) -> SignatureScheme {
+        self.scheme
+    }
+}
+
+/// Presents the device certificate chain, signing with a [`DeviceKeySigner`].
+#[derive(Debug)]
+pub struct DeviceCertResolver(Arc<CertifiedKey>);
+
+impl DeviceCertResolver {
+    pub fn new(certs: Vec<CertificateDer<'static>>, signer: Arc<dyn DeviceKeySigner>) -> Self {
+        Self(Arc::new(CertifiedKey::new(
+            certs,
+            Arc::new(SignerKey(signer)),
+        )))
+    }
+}
+
+impl ResolvesClientCert for DeviceCertResolver {
+    fn resolve(
+        &self,
+        _root_hint_subjects: &[&[u8]],
+        _sigschemes: &[SignatureScheme],
+    ) -> Option<Arc<CertifiedKey>> {
+        Some(self.0.clone())
+    }
+
+    fn has_certs(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustls::pki_types::PrivatePkcs8KeyDer;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn key_der(key: &rcgen::KeyPair) -> PrivateKeyDer<'static> {
+        PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key.serialize_der()))
+    }
+
+    /// Wraps another signer and counts how often it is asked to sign.
+    #[derive(Debug)]
+    struct CountingSigner {
+        inner: PemKeySigner,
+        calls: Arc<AtomicUsize>,
+    }
+
+    impl DeviceKeySigner for CountingSigner {
+        fn algorithm(&self) -> SignatureAlgorithm {
+            self.inner.algorithm()
+        }
+
+        fn schemes(&self) -> Vec<SignatureScheme> {
+            self.inner.schemes()
+        }
+
+        fn sign(&self, scheme: SignatureScheme, message: &[u8]) -> Result<Vec<u8>, SignerError> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            self.inner.sign(scheme, message)
+        }
+    }
+
+    #[test]
+    fn detects_key_type_from_cert() {
+        let key = rcgen::KeyPair::generate_for(&rcgen::PKCS_ECDSA_P384_SHA384).unwrap();
+        let params = rcgen::CertificateParams::new(vec!["device".to_string()]).unwrap();
+        let cert = params.clone().self_signed(&key).unwrap();
+        assert_eq!(
+            KeyType::from_cert(cert.der()).unwrap(),
+            KeyType::EcdsaP384
+        );
+
+        let key = rcgen::KeyPair::generate_for(&rcgen::PKCS_ED25519).unwrap();
+        let cert = params.self_signed(&key).unwrap();
+        assert_eq!(KeyType::from_cert(cert.der()).unwrap(), KeyType::Ed25519);
+    }
+
+    #[test]
+    fn pem_signer_schemes() {
+        let key = rcgen::KeyPair::generate().unwrap();
+        let signer = PemKeySigner::new(&key_der(&key)).unwrap();
+        assert_eq!(signer.algorithm(), SignatureAlgorithm::ECDSA);
+        assert_eq!(signer.schemes(), vec![SignatureScheme::ECDSA_NISTP256_SHA256]);
+        assert!(signer
+            .sign(SignatureScheme::ECDSA_NISTP256_SHA256, b"hello")
+            .is_ok());
+        assert!(matches!(
+            signer.sign(SignatureScheme::ED25519, b"hello"),
+            Err(SignerError::UnsupportedScheme(_))
+        ));
+    }
+
+    #[test]
+    fn command_signer_protocol() {
+        // Echo the scheme name followed by the message back as the "signature"
+        let signer = CommandSigner::new(
+            "printf '%s:' \"$HUB_LINK_SIGN_SCHEME\"; cat".to_string(),
+            KeyType::EcdsaP256,
+        );
+        let sig = signer
+            .sign(SignatureScheme::ECDSA_NISTP256_SHA256, b"message")
+            .unwrap();
+        assert_eq!(sig, b"ecdsa_secp256r1_sha256:message");
+    }
+
+    #[test]
+    fn command_signer_failure() {
+        let signer = CommandSigner::new("echo boom >&2; exit 3".to_string(), KeyType::Rsa);
+        let err = signer
+            .sign(SignatureScheme::RSA_PSS_SHA256, b"message")
+            .unwrap_err();
+        assert!(err.to_string().contains("boom"));
+
+        let signer = CommandSigner::new("true".to_string(), KeyType::Rsa);
+        assert!(signer.sign(SignatureScheme::RSA_PSS_SHA256, b"m").is_err());
+    }
+
+    #[test]
+    fn command_signer_times_out() {
+        let signer = CommandSigner {
+            timeout: Duration::from_millis(100),
+            ..CommandSigner::new("sleep 5".to_string(), KeyType::Rsa)
+        };
+        let started = Instant::now();
+        let err = signer
+            .sign(SignatureScheme::RSA_PSS_SHA256, b"message")
+            .unwrap_err();
+        assert!(err.to_string().contains("timed out"), "{}", err);
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn command_signer_does_not_stall_runtime() {
+        let ticks = Arc::new(std::sync::atomic::AtomicU32::new(0));
+        let counter = ticks.clone();
+        let ticker = tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            }
+        });
+        // Signing on the only worker, as a handshake would
+        let signer = CommandSigner::new("sleep 0.5; cat".to_string(), KeyType::Rsa);
+        let sig = tokio::spawn(async move {
+            signer.sign(SignatureScheme::RSA_PSS_SHA256, b"message")
+        })
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(sig, b"message");
+        assert!(ticks.load(std::sync::atomic::Ordering::SeqCst) >= 10);
+        ticker.abort();
+    }
+
+    #[test]
+    fn command_signer_rejects_foreign_scheme() {
+        let signer = CommandSigner::new("cat".to_string(), KeyType::EcdsaP256);
+        let key = SignerKey(Arc::new(signer));
+        assert!(key.choose_scheme(&[SignatureScheme::ED25519]).is_none());
+        assert!(key
+            .choose_scheme(&[SignatureScheme::ECDSA_NISTP256_SHA256])
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn mtls_handshake_uses_signer() {
+        // CA issuing both the server and the device certificate
+        let ca_key = rcgen::KeyPair::generate().unwrap();
+        let mut ca_params = rcgen::CertificateParams::new(Vec::<String>::new()).unwrap();
+        ca_params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        let ca_cert = ca_params.self_signed(&ca_key).unwrap();
+
+        let server_key = rcgen::KeyPair::generate().unwrap();
+        let server_cert = rcgen::CertificateParams::new(vec!["localhost".to_string()])
+            .unwrap()
+            .signed_by(&server_key, &ca_cert, &ca_key)
+            .unwrap();
+
+        let device_key = rcgen::KeyPair::generate().unwrap();
+        let device_cert = rcgen::CertificateParams::new(vec!["device".to_string()])
+            .unwrap()
+            .signed_by(&device_key, &ca_cert, &ca_key)
+            .unwrap();
+
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(ca_cert.der().clone()).unwrap();
+        let roots = Arc::new(roots);
+
+        let verifier = rustls::server::WebPkiClientVerifier::builder(roots.clone())
+            .build()
+            .unwrap();
+        let server_config = rustls::ServerConfig::builder()
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(vec![server_cert.der().clone()], key_der(&server_key))
+            .unwrap();
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let signer = CountingSigner {
+            inner: PemKeySigner::new(&key_der(&device_key)).unwrap(),
+            calls: calls.clone(),
+        };
+        let resolver = DeviceCertResolver::new(vec![device_cert.der().clone()], Arc::new(signer));
+        let client_config = rustls::ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_client_cert_resolver(Arc::new(resolver));
+
+        let (client_io, server_io) = tokio::io::duplex(16 * 1024);
+        let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(server_config));
+        let server = tokio::spawn(async move {
+            let mut tls = acceptor.accept(server_io).await.unwrap();
+            let mut buf = [0u8; 4];
+            tls.read_exact(&mut buf).await.unwrap();
+            buf
+        });
+
+        let connector = tokio_rustls::TlsConnector::from(Arc::new(client_config));
+        let name = rustls::pki_types::ServerName::try_from("localhost").unwrap();
+        let mut tls = connector.connect(name, client_io).await.unwrap();
+        tls.write_all(b"ping").await.unwrap();
+        tls.flush().await.unwrap();
+
+        assert_eq!(&server.await.unwrap(), b"ping");
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,152 @@
+use crate::config::ReconnectConfig;
+use std::time::Duration;
+
+/// Doublings after which the delay is pinned at the maximum anyway.
+const MAX_ATTEMPT: u32 = 32;
+
+/// Exponential reconnect backoff with jitter.
+///
+/// The delay doubles from `min` up to `max` with every failed attempt and
+/// only goes back to `min` after a connection stayed up for `stable_after`.
+#[derive(Debug)]
+pub struct Backoff {
+    min: Duration,
+    max: Duration,
+    jitter: f64,
+    stable_after: Duration,
+    auth_failure_delay: Duration,
+    attempt: u32,
+}
+
+impl Backoff {
+    pub fn new(config: &ReconnectConfig) -> Self {
+        Self {
+            min: Duration::from_secs(config.min_delay_secs()),
+            max: Duration::from_secs(config.max_delay_secs()),
+            jitter: config.jitter(),
+            stable_after: Duration::from_secs(config.stable_after_secs()),
+            auth_failure_delay: Duration::from_secs(config.auth_failure_delay_secs()),
+            attempt: 0,
+        }
+    }
+
+    /// Delay before the next attempt, including jitter.
+    pub fn delay(&self) -> Duration {
+        let base = self
+            .min
+            .saturating_mul(2u32.saturating_pow(self.attempt))
+            .min(self.max);
+        base.mul_f64(1.0 + rand::random::<f64>() * self.jitter)
+    }
+
+    /// Delay before the next attempt, honoring a server retry hint if it
+    /// asks for a longer wait.
+    pub fn delay_with_hint(&self, retry_after: Option<Duration>) -> Duration {
+        let delay = self.delay();
+        retry_after.map_or(delay, |hint| hint.max(delay))
+    }
+
+    /// Delay after the server refused the device: at least the configured
+    /// auth failure delay, since retrying sooner only hammers the server.
+    pub fn auth_failure_delay(&self) -> Duration {
+        self.auth_failure_delay.max(self.delay())
+    }
+
+    /// Count a failed attempt, doubling the next delay.
+    pub fn escalate(&mut self) {
+        self.attempt = (self.attempt + 1).min(MAX_ATTEMPT);
+    }
+
+    /// Record how long the last connection was joined. A stable connection
+    /// resets the backoff, however it ended.
+    pub fn connection_ended(&mut self, joined_for: Option<Duration>) {
+        if joined_for.is_some_and(|d| d >= self.stable_after) {
+            self.attempt = 0;
+        }
+    }
+
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn backoff() -> Backoff {
+        Backoff::new(&ReconnectConfig::default())
+    }
+
+    #[test]
+    fn backoff_delay_increases() {
+        let mut backoff = backoff();
+        // Defaults: 1s doubling, with up to 50% jitter
+        assert!(backoff.delay() <= Duration::from_secs_f64(1.5));
+        backoff.escalate();
+        assert!(backoff.delay() <= Duration::from_secs(3));
+        backoff.escalate();
+        backoff.escalate();
+        let d3 = backoff.delay();
+        assert!(d3 >= Duration::from_secs(8) && d3 <= Duration::from_secs(12));
+    }
+
+    #[test]
+    fn backoff_delay_caps() {
+        let mut backoff = backoff();
+        for _ in 0..100 {
+            backoff.escalate();
+        }
+        // Base capped at 60s, with 50% jitter max is 90s
+        assert!(backoff.delay() <= Duration::from_secs(90));
+        assert!(backoff.delay() >= Duration::from_secs(60));
+    }
+
+    #[test]
+    fn custom_policy_without_jitter() {
+        let config = ReconnectConfig {
+            min_delay_secs: Some(5),
+            max_delay_secs: Some(30),
+            jitter: Some(0.0),
+            stable_after_secs: None,
+            ..Default::default()
+        };
+        let mut backoff = Backoff::new(&config);
+        assert_eq!(backoff.delay(), Duration::from_secs(5));
+        backoff.escalate();
+        assert_eq!(backoff.delay(), Duration::from_secs(10));
+        backoff.escalate();
+        backoff.escalate();
+        assert_eq!(backoff.delay(), Duration::from_secs(30));
+    }
+
+    #[test]
+    fn resets_only_after_stable_connection() {
+        let mut backoff = backoff();
+        backoff.escalate();
+        backoff.escalate();
+
+        // Never joined, or accepted and dropped right away
+        backoff.connection_ended(None);
+        backoff.connection_ended(Some(Duration::from_secs(2)));
+        assert_eq!(backoff.attempt(), 2);
+
+        backoff.connection_ended(Some(Duration::from_secs(600)));
+        assert_eq!(backoff.attempt(), 0);
+    }
+
+    #[test]
+    fn auth_failure_waits_longer() {
+        let backoff = backoff();
+        assert!(backoff.auth_failure_delay() >= Duration::from_secs(3600));
+    }
+
+    #[test]
+    fn retry_hint_extends_delay() {
+        let backoff = backoff();
+        let hinted = backoff.delay_with_hint(Some(Duration::from_secs(120)));
+        assert_eq!(hinted, Duration::from_secs(120));
+        // A hint shorter than the backoff doesn't shorten it
+        assert!(backoff.delay_with_hint(Some(Duration::ZERO)) >= Duration::from_secs(1));
+    }
+}
@@ -1,12 +1,18 @@
+use crate::auth::mtls;
 use crate::auth::shared_secret::SharedSecretAuth;
 use crate::channel::{ChannelBuilder, Message};
 use crate::config::{AuthConfig, Config};
-use crate::firmware::{self, UpdateInfo};
+use crate::firmware::{self, DownloadControl, UpdateInfo};
+use crate::net::{self, Dialer};
 use crate::serial;
+use crate::state::{StateStore, UpdatePhase};
+use crate::status::SharedStatus;
+use crate::update::{UpdateReport, Updater};
+use crate::validation::FirmwareValidator;
 use futures_util::{SinkExt, StreamExt};
 use serde_json::json;
 use thiserror::Error;
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, Mutex};
 use tokio::time::{Duration, Instant};
 use tracing::{debug, error, info, warn};
 use tungstenite::http;
@@ -23,28 +29,100 @@ pub enum ClientError {
     Serial(#[from] serial::SerialError),
     #[error("auth error: {0}")]
     Auth(String),
+    #[error("unauthorized: {0}")]
+    Unauthorized(String),
+    #[error("device unknown to the server: {0}")]
+    DeviceUnknown(String),
+    #[error("rate limited by the server")]
+    RateLimited {
+        /// From the `Retry-After` header, if the server sent one.
+        retry_after: Option<Duration>,
+    },
+    #[error("server error: HTTP {status}")]
+    ServerError {
+        status: u16,
+        retry_after: Option<Duration>,
+    },
+    #[error("server rejected connection: HTTP {status}")]
+    Rejected {
+        status: u16,
+        retry_after: Option<Duration>,
+    },
     #[error("firmware error: {0}")]
     Firmware(#[from] firmware::FirmwareError),
+    #[error("network error: {0}")]
+    Network(#[from] net::NetError),
     #[error("channel closed")]
     ChannelClosed,
+    #[error("no pong from server within {0:?}")]
+    PongTimeout(Duration),
+}
+
+type WsStream = tokio_tungstenite::WebSocketStream<
+    tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>,
+>;
+
+impl ClientError {
+    /// How long the server asked us to wait before reconnecting.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            ClientError::RateLimited { retry_after }
+            | ClientError::ServerError { retry_after, .. }
+            | ClientError::Rejected { retry_after, .. } => *retry_after,
+            _ => None,
+        }
+    }
+
+    /// The server refused this device; retrying soon will not help.
+    pub fn is_auth_failure(&self) -> bool {
+        matches!(
+            self,
+            ClientError::Unauthorized(_) | ClientError::DeviceUnknown(_)
+        )
+    }
+}
+
+/// How a call to [`NervesHubClient::run`] ended.
+#[derive(Debug)]
+pub struct SessionEnd {
+    /// How long the device channel was joined; `None` if it never was.
+    pub joined_for: Option<Duration>,
+    pub result: Result<(), ClientError>,
 }
 
 /// Events that the client can emit to the caller.
 #[derive(Debug)]
 pub enum ClientEvent {
-    Connected,
+    /// Connected to the given host.
+    Connected(String),
     Joined,
     UpdateAvailable(UpdateInfo),
     FirmwareDownloaded(std::path::PathBuf),
     FirmwareApplied,
+    /// The running update was cancelled, with the reason.
+    UpdateCancelled(String),
     RebootRequested,
     Disconnected(String),
+    /// The device certificate expires within the warning window
+    /// (`days_remaining` is negative once it has expired).
+    CertificateExpiring { serial: String, days_remaining: i64 },
 }
 
+/// How often the device certificate is re-checked on a long-lived connection.
+const CERT_CHECK_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
 /// The NervesHub device client.
 pub struct NervesHubClient {
     config: Config,
     serial: String,
+    status: SharedStatus,
+    dialer: Dialer,
+    downloads: DownloadControl,
+    state: StateStore,
+    validator: FirmwareValidator,
+    updater: Updater,
+    /// Reports from background updates, sent by the current connection.
+    reports: Mutex<mpsc::Receiver<UpdateReport>>,
 }
 
 impl NervesHubClient {
@@ -54,7 +132,32 @@ impl NervesHubClient {
             config.serial_number_command.as_deref(),
         )?;
         info!(serial = %serial, "resolved device serial number");
-        Ok(Self { config, serial })
+        let dialer = Dialer::from_config(&config)?;
+        let http = dialer.http_client()?;
+        let state = StateStore::new(&config.data_dir());
+        // An update applied before a reboot is decided before connecting,
+        // so the validation watchdog runs even if the server is unreachable
+        let mut saved = state.load();
+        if saved.settle(&config.firmware.uuid, state.boot_id()) {
+            if let Err(e) = state.save(&saved) {
+                warn!(error = %e, "failed to save update state");
+            }
+        }
+        let validator = FirmwareValidator::new(&config.update(), config.fwup_devpath());
+        let downloads = DownloadControl::new();
+        let (updater, reports) =
+            Updater::new(&config, http, state.clone(), downloads.clone());
+        Ok(Self {
+            config,
+            serial,
+            status: SharedStatus::new(),
+            dialer,
+            downloads,
+            state,
+            validator,
+            updater,
+            reports: Mutex::new(reports),
+        })
     }
 
     #[allow(dead_code)]
@@ -62,6 +165,49 @@ impl NervesHubClient {
         &self.serial
     }
 
+    /// Handle to the client status, for reporting over the control socket.
+    pub fn status(&self) -> SharedStatus {
+        self.status.clone()
+    }
+
+    /// Pause switch and cancellation for firmware updates, for the control
+    /// socket and shutdown.
+    pub fn downloads(&self) -> DownloadControl {
+        self.downloads.clone()
+    }
+
+    /// Watchdog that reverts new firmware if it isn't validated in time.
+    /// `None` unless the device runs unvalidated new firmware and a
+    /// validation timeout is configured.
+    pub fn validation_watchdog(&self) -> Option<impl std::future::Future<Output = ()> + Send + 'static> {
+        let timeout = self.validator.timeout()?;
+        self.state
+            .load()
+            .update
+            .filter(|update| update.phase == UpdatePhase::Booted)?;
+        let validator = self.validator.clone();
+        let store = self.state.clone();
+        Some(async move {
+            if !validator.expired(timeout).await {
+                return;
+            }
+            // Saved first: the revert usually reboots
+            let reason = format!("firmware not validated within {}s, reverted", timeout.as_secs());
+            let saved = store.update(|state| {
+                if let Some(update) = &mut state.update {
+                    update.phase = UpdatePhase::Failed;
+                    update.error = Some(reason);
+                }
+            });
+            if let Err(e) = saved {
+                warn!(error = %e, "failed to save update state");
+            }
+            if let Err(e) = validator.revert().await {
+                error!(error = %e, "failed to revert firmware");
+            }
+        })
+    }
+
     /// Build the join payload with firmware metadata.
     pub fn join_payload(&self) -> serde_json::Value {
         json!({
@@ -71,17 +217,41 @@ impl NervesHubClient {
             "nerves_fw_platform": self.config.firmware.platform,
             "nerves_fw_architecture": self.config.firmware.architecture,
             "nerves_fw_product": self.config.firmware.product,
+            "delta_updates": self.config.update().delta(),
         })
     }
 
-    /// Connect to the NervesHub server and run the event loop.
+    /// Connect to the NervesHub server on `host` and run the event loop.
     /// Sends events through the returned channel.
-    pub async fn run(
+    pub async fn run(&self, host: &str, event_tx: mpsc::Sender<ClientEvent>) -> SessionEnd {
+        let mut joined_at = None;
+        let result = self.run_connection(host, &event_tx, &mut joined_at).await;
+        self.status.update(|s| {
+            s.connected = false;
+            s.joined = false;
+            s.host = None;
+            s.auth_method = None;
+        });
+        SessionEnd {
+            joined_for: joined_at.map(|at: Instant| at.elapsed()),
+            result,
+        }
+    }
+
+    async fn run_connection(
         &self,
-        event_tx: mpsc::Sender<ClientEvent>,
+        host: &str,
+        event_tx: &mpsc::Sender<ClientEvent>,
+        joined_at: &mut Option<Instant>,
     ) -> Result<(), ClientError> {
-        let ws_stream = self.connect().await?;
-        let _ = event_tx.send(ClientEvent::Connected).await;
+        self.check_certificate(event_tx).await;
+
+        let ws_stream = self.connect(host).await?;
+        self.status.update(|s| {
+            s.connected = true;
+            s.host = Some(host.to_string());
+        });
+        let _ = event_tx.send(ClientEvent::Connected(host.to_string())).await;
 
         let (mut write, mut read) = ws_stream.split();
 
@@ -92,7 +262,7 @@ impl NervesHubClient {
         // Send join
         let join_msg = channel.join(self.join_payload());
         write
-            .send(tungstenite::Message::Text(join_msg.to_json().into()))
+            .send(tungstenite::Message::Text(join_msg.to_json()))
             .await
             .map_err(|e| ClientError::WebSocket(e.to_string()))?;
         info!(topic = %topic, "sent channel join");
@@ -100,20 +270,24 @@ impl NervesHubClient {
         // Wait for join reply
         let join_reply = Self::wait_for_reply(&mut read, &channel.join_ref).await?;
         if !join_reply.reply_ok() {
-            let reason = join_reply
-                .payload
-                .get("response")
-                .and_then(|r| r.get("reason"))
-                .and_then(|r| r.as_str())
-                .unwrap_or("unknown");
-            return Err(ClientError::JoinRejected(reason.to_string()));
+            return Err(join_error(&join_reply));
         }
         info!("joined device channel");
+        *joined_at = Some(Instant::now());
+        self.status.update(|s| s.joined = true);
         let _ = event_tx.send(ClientEvent::Joined).await;
+        self.report_previous_update(&channel, &mut write).await;
 
         // Event loop: heartbeat + message handling
         let heartbeat_interval = Duration::from_secs(self.config.heartbeat_interval_secs());
         let mut next_heartbeat = Instant::now() + heartbeat_interval;
+        let mut next_cert_check = Instant::now() + CERT_CHECK_INTERVAL;
+        let keepalive = self.config.keepalive();
+        let ping_interval = keepalive.ping_interval_secs.map(Duration::from_secs);
+        let pong_timeout = Duration::from_secs(keepalive.pong_timeout_secs());
+        let mut next_ping = ping_interval.map(|interval| Instant::now() + interval);
+        let mut pong_deadline: Option<Instant> = None;
+        let mut reports = self.reports.lock().await;
 
         loop {
             tokio::select! {
@@ -122,7 +296,7 @@ impl NervesHubClient {
                         Some(Ok(tungstenite::Message::Text(text))) => {
                             match Message::from_json(&text) {
                                 Ok(msg) => {
-                                    self.handle_message(msg, &channel, &mut write, &event_tx).await?;
+                                    self.handle_message(msg, &channel, &mut write, event_tx).await?;
                                 }
                                 Err(e) => {
                                     warn!(error = %e, "failed to parse message");
@@ -134,8 +308,11 @@ impl NervesHubClient {
                             let _ = event_tx.send(ClientEvent::Disconnected("connection closed".to_string())).await;
                             return Ok(());
                         }
+                        Some(Ok(tungstenite::Message::Pong(_))) => {
+                            pong_deadline = None;
+                        }
                         Some(Ok(_)) => {
-                            // Ping/Pong/Binary - ignore
+                            // Ping (answered by tungstenite)/Binary - ignore
                         }
                         Some(Err(e)) => {
                             error!(error = %e, "websocket error");
@@ -144,54 +321,158 @@ impl NervesHubClient {
                         }
                     }
                 }
+                Some(report) = reports.recv() => {
+                    // Built here so pushes carry this connection's join ref
+                    let msg = match report {
+                        UpdateReport::Progress(pct) => channel.push("fwup_progress", json!({"value": pct})),
+                        UpdateReport::Status(payload) => channel.push("status_update", payload),
+                    };
+                    write
+                        .send(tungstenite::Message::Text(msg.to_json()))
+                        .await
+                        .map_err(|e| ClientError::WebSocket(e.to_string()))?;
+                }
                 _ = tokio::time::sleep_until(next_heartbeat) => {
                     let hb = channel.heartbeat();
                     write
-                        .send(tungstenite::Message::Text(hb.to_json().into()))
+                        .send(tungstenite::Message::Text(hb.to_json()))
                         .await
                         .map_err(|e| ClientError::WebSocket(e.to_string()))?;
                     debug!("sent heartbeat");
                     next_heartbeat = Instant::now() + heartbeat_interval;
                 }
+                _ = tokio::time::sleep_until(next_ping.unwrap_or_else(Instant::now)), if next_ping.is_some() => {
+                    write
+                        .send(tungstenite::Message::Ping(Vec::new()))
+                        .await
+                        .map_err(|e| ClientError::WebSocket(e.to_string()))?;
+                    debug!("sent ping");
+                    // Keep the earliest deadline if a pong is still outstanding
+                    pong_deadline.get_or_insert(Instant::now() + pong_timeout);
+                    next_ping = ping_interval.map(|interval| Instant::now() + interval);
+                }
+                _ = tokio::time::sleep_until(pong_deadline.unwrap_or_else(Instant::now)), if pong_deadline.is_some() => {
+                    warn!(timeout_secs = pong_timeout.as_secs(), "no pong from server");
+                    let _ = event_tx.send(ClientEvent::Disconnected("pong timeout".to_string())).await;
+                    return Err(ClientError::PongTimeout(pong_timeout));
+                }
+                _ = tokio::time::sleep_until(next_cert_check) => {
+                    self.check_certificate(event_tx).await;
+                    next_cert_check = Instant::now() + CERT_CHECK_INTERVAL;
+                }
             }
         }
     }
 
-    async fn connect(
-        &self,
-    ) -> Result<
-        tokio_tungstenite::WebSocketStream<
-            tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>,
-        >,
-        ClientError,
-    > {
-        let url = self.config.socket_url();
+    /// Read the device certificate's validity, record it in the status and
+    /// emit `CertificateExpiring` when it is inside the warning window.
+    ///
+    /// Only applies to the first mTLS method. Failures are logged; the TLS setup in `connect`
+    /// reports the actual error.
+    async fn check_certificate(&self, event_tx: &mpsc::Sender<ClientEvent>) {
+        let Some((cert_path, pkcs12_password_file)) =
+            self.config.auth.iter().find_map(|auth| match auth {
+                AuthConfig::Mtls {
+                    cert_path,
+                    pkcs12_password_file,
+                    ..
+                } => Some((cert_path, pkcs12_password_file)),
+                _ => None,
+            })
+        else {
+            return;
+        };
+        let cert = match mtls::cert_info(cert_path, pkcs12_password_file.as_deref()) {
+            Ok(cert) => cert,
+            Err(e) => {
+                warn!(error = %e, "failed to inspect device certificate");
+                return;
+            }
+        };
+
+        let days_remaining = cert.days_remaining();
+        let previous_serial = self.status.snapshot().cert_serial;
+        if previous_serial.is_some_and(|serial| serial != cert.serial) {
+            info!(serial = %cert.serial, "device certificate rotated");
+        }
+        debug!(serial = %cert.serial, days_remaining, "device certificate validity");
+
+        self.status.update(|s| {
+            s.cert_serial = Some(cert.serial.clone());
+            s.cert_not_after = Some(cert.not_after);
+            s.cert_days_remaining = Some(days_remaining);
+        });
+
+        if days_remaining <= self.config.cert_expiry_warning_days() {
+            let _ = event_tx
+                .send(ClientEvent::CertificateExpiring {
+                    serial: cert.serial,
+                    days_remaining,
+                })
+                .await;
+        }
+    }
+
+    /// Connect using the configured auth methods in order.
+    ///
+    /// A method whose credentials cannot be loaded or whose TLS handshake or
+    /// WebSocket upgrade is rejected falls through to the next one. Other
+    /// failures (DNS, refused connection) are returned right away since the
+    /// next method would hit them too.
+    async fn connect(&self, host: &str) -> Result<WsStream, ClientError> {
+        let url = self
+            .config
+            .socket_url(host)
+            .map_err(|e| ClientError::Connection(e.to_string()))?;
         info!(url = %url, "connecting to NervesHub");
 
-        match &self.config.auth {
+        let mut methods = self.config.auth.iter().peekable();
+        while let Some(auth) = methods.next() {
+            match self.connect_with(auth, &url).await {
+                Ok(ws_stream) => {
+                    info!(method = auth.name(), "authenticated");
+                    self.status
+                        .update(|s| s.auth_method = Some(auth.name().to_string()));
+                    return Ok(ws_stream);
+                }
+                Err(e @ (ClientError::Auth(_) | ClientError::Unauthorized(_)))
+                    if methods.peek().is_some() =>
+                {
+                    warn!(method = auth.name(), error = %e, "auth method failed, trying next");
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Err(ClientError::Auth("no auth methods configured".to_string()))
+    }
+
+    async fn connect_with(&self, auth: &AuthConfig, url: &str) -> Result<WsStream, ClientError> {
+        use tungstenite::client::IntoClientRequest;
+        let mut request = url
+            .into_client_request()
+            .map_err(|e| ClientError::Connection(e.to_string()))?;
+
+        let connector = match auth {
             AuthConfig::Mtls {
                 cert_path,
                 key_path,
                 ca_cert_path,
+                sign_command,
+                pkcs12_password_file,
             } => {
-                let tls_config =
-                    crate::auth::mtls::build_tls_config(cert_path, key_path, ca_cert_path)
-                        .map_err(|e| ClientError::Auth(e.to_string()))?;
-
-                let connector =
-                    tokio_tungstenite::Connector::Rustls(tls_config);
-
-                let (ws_stream, _response) =
-                    tokio_tungstenite::connect_async_tls_with_config(
-                        &url,
-                        None,
-                        false,
-                        Some(connector),
-                    )
-                    .await
-                    .map_err(|e| ClientError::Connection(e.to_string()))?;
-
-                Ok(ws_stream)
+                // Loaded from disk on every connect so a rotated cert/key
+                // is picked up on the next reconnect.
+                let identity = mtls::load_identity(&mtls::DeviceCredentials {
+                    cert_path: cert_path.clone(),
+                    key_path: key_path.clone(),
+                    sign_command: sign_command.clone(),
+                    pkcs12_password_file: pkcs12_password_file.clone(),
+                })
+                .map_err(|e| ClientError::Auth(e.to_string()))?;
+                let tls_config = mtls::build_tls_config(identity, ca_cert_path)
+                    .map_err(|e| ClientError::Auth(e.to_string()))?;
+
+                Some(tokio_tungstenite::Connector::Rustls(tls_config))
             }
             AuthConfig::SharedSecret { key, secret } => {
                 let auth = SharedSecretAuth::new(key.clone(), secret.clone());
@@ -199,12 +480,6 @@ impl NervesHubClient {
                     .auth_headers(&self.serial)
                     .map_err(|e| ClientError::Auth(e.to_string()))?;
 
-                // Build a proper WebSocket request first, then add auth headers
-                use tungstenite::client::IntoClientRequest;
-                let mut request = url
-                    .into_client_request()
-                    .map_err(|e| ClientError::Connection(e.to_string()))?;
-
                 for (name, value) in &auth_headers {
                     request.headers_mut().insert(
                         http::header::HeaderName::from_bytes(name.as_bytes())
@@ -214,14 +489,35 @@ impl NervesHubClient {
                     );
                 }
 
-                let (ws_stream, _response) =
-                    tokio_tungstenite::connect_async(request)
-                        .await
-                        .map_err(|e| ClientError::Connection(e.to_string()))?;
-
-                Ok(ws_stream)
+                // Default connector: server verified against webpki roots
+                None
             }
-        }
+        };
+
+        // Dial TCP ourselves for proxy and keepalive settings
+        let uri = request.uri();
+        let host = uri
+            .host()
+            .ok_or_else(|| ClientError::Connection(format!("no host in {}", url)))?
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_string();
+        let scheme = uri.scheme_str().unwrap_or("wss");
+        let port = uri
+            .port_u16()
+            .unwrap_or(if scheme == "wss" { 443 } else { 80 });
+        let stream = self
+            .dialer
+            .connect(scheme, &host, port)
+            .await
+            .map_err(|e| ClientError::Connection(e.to_string()))?;
+
+        let (ws_stream, _response) =
+            tokio_tungstenite::client_async_tls_with_config(request, stream, None, connector)
+                .await
+                .map_err(connect_error)?;
+
+        Ok(ws_stream)
     }
 
     async fn wait_for_reply<S>(
@@ -278,7 +574,7 @@ impl NervesHubClient {
                             .send(ClientEvent::UpdateAvailable(update_info.clone()))
                             .await;
                         self.handle_update(update_info, channel, write, event_tx)
-                            .await?;
+                            .await;
                     }
                     Err(e) => {
                         warn!(error = %e, "failed to parse update message");
@@ -287,10 +583,20 @@ impl NervesHubClient {
             }
             "reboot" => {
                 info!("received reboot command");
+                let saved = self.state.update(|state| {
+                    if let Some(update) = &mut state.update {
+                        if update.phase == UpdatePhase::Applied {
+                            update.phase = UpdatePhase::AwaitingReboot;
+                        }
+                    }
+                });
+                if let Err(e) = saved {
+                    warn!(error = %e, "failed to save update state");
+                }
                 // Acknowledge reboot
                 let ack = channel.push("rebooting", json!({}));
                 let _ = write
-                    .send(tungstenite::Message::Text(ack.to_json().into()))
+                    .send(tungstenite::Message::Text(ack.to_json()))
                     .await;
                 let _ = event_tx.send(ClientEvent::RebootRequested).await;
             }
@@ -320,107 +626,185 @@ impl NervesHubClient {
         Ok(())
     }
 
+    /// Validate new firmware after its first join, and report how an update
+    /// applied before the last reboot turned out, once per update.
+    async fn report_previous_update<S>(&self, channel: &ChannelBuilder, write: &mut S)
+    where
+        S: SinkExt<tungstenite::Message> + Unpin,
+        S::Error: std::fmt::Display,
+    {
+        let Some(mut update) = self.state.load().update else {
+            return;
+        };
+        // Reaching the server is what new firmware has to prove
+        if update.phase == UpdatePhase::Booted {
+            if let Err(e) = self.validator.validate().await {
+                error!(error = %e, "firmware validation failed");
+                return;
+            }
+            update.phase = UpdatePhase::Validated;
+            if let Err(e) = self.state.set_phase(UpdatePhase::Validated) {
+                warn!(error = %e, "failed to save update state");
+            }
+        }
+        let payload = match update.phase {
+            UpdatePhase::Validated => {
+                info!(uuid = %update.uuid, version = %update.version, "firmware update succeeded");
+                json!({"status": "update-succeeded", "uuid": update.uuid, "version": update.version})
+            }
+            UpdatePhase::Failed => {
+                let reason = update.error.clone().unwrap_or_default();
+                warn!(uuid = %update.uuid, reason = %reason, "firmware update failed");
+                json!({"status": "update-failed", "uuid": update.uuid, "reason": reason})
+            }
+            _ => return,
+        };
+        let msg = channel.push("status_update", payload);
+        match write.send(tungstenite::Message::Text(msg.to_json())).await {
+            Ok(()) => {
+                if let Err(e) = self.state.update(|state| state.update = None) {
+                    warn!(error = %e, "failed to save update state");
+                }
+            }
+            // Kept for the next connection
+            Err(e) => warn!(error = %e, "failed to report update outcome"),
+        }
+    }
+
+    /// Check an update and start it in the background. Updates that need
+    /// no download are answered right away.
     async fn handle_update<S>(
         &self,
         update_info: UpdateInfo,
         channel: &ChannelBuilder,
         write: &mut S,
         event_tx: &mpsc::Sender<ClientEvent>,
-    ) -> Result<(), ClientError>
-    where
+    ) where
         S: SinkExt<tungstenite::Message> + Unpin,
         S::Error: std::fmt::Display,
     {
-        info!(
-            uuid = %update_info.firmware_meta.uuid,
-            version = %update_info.firmware_meta.version,
-            "downloading firmware"
-        );
-
-        // Download firmware
-        let data_dir = self
-            .config
-            .data_dir
-            .clone()
-            .unwrap_or_else(|| std::path::PathBuf::from("/tmp/hub_link"));
-        tokio::fs::create_dir_all(&data_dir)
-            .await
-            .map_err(|e| firmware::FirmwareError::Io(e))?;
-
-        let channel_topic = channel.topic.clone();
-        let channel_join_ref = channel.join_ref.clone();
+        let meta = &update_info.firmware_meta;
+        if meta.uuid == self.config.firmware.uuid {
+            info!(uuid = %meta.uuid, "firmware already running, nothing to apply");
+            report_handled(channel, write).await;
+            return;
+        }
+        if self.state.load().is_pending(&meta.uuid, self.state.boot_id()) {
+            info!(uuid = %meta.uuid, "firmware already applied, waiting for reboot");
+            report_handled(channel, write).await;
+            return;
+        }
 
-        // We need to send progress updates. We'll collect them and send after download.
-        let mut last_reported_percent: u8 = 0;
-        let (progress_tx, mut progress_rx) = mpsc::channel::<u8>(16);
+        if let Err(e) = update_info
+            .check_compatible(&self.config.firmware, self.config.update().reject_downgrade())
+        {
+            error!(error = %e, "firmware update rejected");
+            report_failure(channel, write, &e).await;
+            return;
+        }
 
-        let url = update_info.firmware_url.clone();
-        let data_dir_clone = data_dir.clone();
+        self.updater.start(update_info, event_tx.clone());
+    }
+}
 
-        let download_handle = tokio::spawn(async move {
-            firmware::download_firmware(&url, &data_dir_clone, |downloaded, total| {
-                let pct = firmware::progress_percent(downloaded, total);
-                let _ = progress_tx.try_send(pct);
-            })
-            .await
-        });
+/// Tell the server the update is done on our side.
+async fn report_handled<S>(channel: &ChannelBuilder, write: &mut S)
+where
+    S: SinkExt<tungstenite::Message> + Unpin,
+{
+    let msg = channel.push("status_update", json!({"status": "update-handled"}));
+    let _ = write.send(tungstenite::Message::Text(msg.to_json())).await;
+}
 
-        // Forward progress while download is running
-        loop {
-            tokio::select! {
-                pct = progress_rx.recv() => {
-                    match pct {
-                        Some(pct) if pct > last_reported_percent + 4 || pct == 100 => {
-                            last_reported_percent = pct;
-                            let progress_msg = json!({"value": pct});
-                            // Build a push message manually to avoid borrow issues
-                            let push = Message {
-                                join_ref: Some(channel_join_ref.clone()),
-                                msg_ref: Some("0".to_string()), // Progress doesn't need unique refs
-                                topic: channel_topic.clone(),
-                                event: "fwup_progress".to_string(),
-                                payload: progress_msg,
-                            };
-                            let _ = write.send(tungstenite::Message::Text(push.to_json().into())).await;
-                        }
-                        Some(_) => {} // Skip small increments
-                        None => break, // Channel closed, download done
-                    }
+/// Map a connect error, telling server-side rejections (TLS alert during
+/// the handshake, HTTP status on the upgrade) apart from other failures.
+fn connect_error(e: tungstenite::Error) -> ClientError {
+    match &e {
+        tungstenite::Error::Http(response) => {
+            let status = response.status();
+            // Only delta-seconds; an HTTP date falls back to the backoff
+            let retry_after = response
+                .headers()
+                .get(http::header::RETRY_AFTER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.trim().parse().ok())
+                .map(Duration::from_secs);
+            match status {
+                http::StatusCode::UNAUTHORIZED | http::StatusCode::FORBIDDEN => {
+                    ClientError::Unauthorized(format!("HTTP {}", status))
                 }
+                http::StatusCode::TOO_MANY_REQUESTS => ClientError::RateLimited { retry_after },
+                status if status.is_server_error() => ClientError::ServerError {
+                    status: status.as_u16(),
+                    retry_after,
+                },
+                status => ClientError::Rejected {
+                    status: status.as_u16(),
+                    retry_after,
+                },
             }
         }
+        tungstenite::Error::Io(io)
+            if matches!(
+                io.get_ref().and_then(|inner| inner.downcast_ref::<rustls::Error>()),
+                Some(rustls::Error::AlertReceived(alert)) if is_auth_alert(alert)
+            ) =>
+        {
+            ClientError::Unauthorized(e.to_string())
+        }
+        _ => ClientError::Connection(e.to_string()),
+    }
+}
 
-        let firmware_path = download_handle
-            .await
-            .map_err(|e| ClientError::Connection(format!("download task failed: {}", e)))?
-            .map_err(ClientError::Firmware)?;
-
-        info!(path = %firmware_path.display(), "firmware downloaded");
-        let _ = event_tx
-            .send(ClientEvent::FirmwareDownloaded(firmware_path.clone()))
-            .await;
-
-        // Apply firmware
-        firmware::apply_firmware(
-            &firmware_path,
-            self.config.fwup_devpath(),
-            self.config.fwup_task(),
-        )
-        .await
-        .map_err(ClientError::Firmware)?;
-
-        let _ = event_tx.send(ClientEvent::FirmwareApplied).await;
-
-        // Report completion
-        let status_msg = channel.push("status_update", json!({"status": "update-handled"}));
-        let _ = write
-            .send(tungstenite::Message::Text(status_msg.to_json().into()))
-            .await;
+/// TLS alerts a server sends when it doesn't accept the device certificate.
+/// Other alerts (handshake failure, protocol version, ...) point at a TLS
+/// setup problem rather than the credentials.
+fn is_auth_alert(alert: &rustls::AlertDescription) -> bool {
+    use rustls::AlertDescription::*;
+    matches!(
+        alert,
+        BadCertificate
+            | CertificateRequired
+            | UnknownCA
+            | CertificateRevoked
+            | CertificateExpired
+            | AccessDenied
+    )
+}
 
-        Ok(())
+/// Map a channel join rejection by its reason.
+fn join_error(reply: &Message) -> ClientError {
+    let reason = reply
+        .payload
+        .get("response")
+        .and_then(|r| r.get("reason"))
+        .and_then(|r| r.as_str());
+    // Only reasons the server sends for these cases; anything else, or no
+    // reason at all, must not trigger the auth failure handling
+    match reason {
+        Some(reason) if ["unauthorized", "forbidden"].contains(&reason) => {
+            ClientError::Unauthorized(reason.to_string())
+        }
+        Some(reason) if reason.eq_ignore_ascii_case("device not found") => {
+            ClientError::DeviceUnknown(reason.to_string())
+        }
+        Some(reason) => ClientError::JoinRejected(reason.to_string()),
+        None => ClientError::JoinRejected("no reason given".to_string()),
     }
 }
 
+/// Tell the server the update failed and why.
+async fn report_failure<S>(channel: &ChannelBuilder, write: &mut S, error: &firmware::FirmwareError)
+where
+    S: SinkExt<tungstenite::Message> + Unpin,
+{
+    let msg = channel.push(
+        "status_update",
+        json!({"status": "update-failed", "reason": error.to_string()}),
+    );
+    let _ = write.send(tungstenite::Message::Text(msg.to_json())).await;
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -428,11 +812,16 @@ mod tests {
 
     fn test_config() -> Config {
         Config {
-            host: "example.com".to_string(),
-            auth: AuthConfig::SharedSecret {
+            hosts: vec!["example.com".to_string()],
+            failover: None,
+            port: None,
+            socket_path: None,
+            tls: None,
+            socket_params: None,
+            auth: vec![AuthConfig::SharedSecret {
                 key: "test-key".to_string(),
                 secret: "test-secret".to_string(),
-            },
+            }],
             serial_number: Some("test-device-001".to_string()),
             serial_number_command: None,
             fwup_devpath: None,
@@ -447,6 +836,18 @@ mod tests {
             heartbeat_interval_secs: None,
             data_dir: None,
             device_api_version: None,
+            cert_expiry_warning_days: None,
+            control_socket: None,
+            provision: None,
+            reconnect: None,
+            keepalive: None,
+            bind: None,
+            dns: None,
+            download: None,
+            update: None,
+            connectivity_check_command: None,
+            connectivity_check_interval_secs: None,
+            proxy: None,
         }
     }
 
@@ -466,6 +867,7 @@ mod tests {
         assert_eq!(payload["nerves_fw_architecture"], "arm");
         assert_eq!(payload["nerves_fw_product"], "test-product");
         assert_eq!(payload["device_api_version"], "2.3.0");
+        assert_eq!(payload["delta_updates"], true);
     }
 
     #[test]
@@ -476,4 +878,589 @@ mod tests {
         let payload = client.join_payload();
         assert_eq!(payload["device_api_version"], "2.0.0");
     }
+
+    #[test]
+    fn classifies_auth_rejection() {
+        let response = http::Response::builder()
+            .status(http::StatusCode::UNAUTHORIZED)
+            .body(None)
+            .unwrap();
+        assert!(matches!(
+            connect_error(tungstenite::Error::Http(response)),
+            ClientError::Unauthorized(_)
+        ));
+
+        let alert = std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            rustls::Error::AlertReceived(rustls::AlertDescription::CertificateRequired),
+        );
+        assert!(matches!(
+            connect_error(tungstenite::Error::Io(alert)),
+            ClientError::Unauthorized(_)
+        ));
+
+        let refused = std::io::Error::from(std::io::ErrorKind::ConnectionRefused);
+        assert!(matches!(
+            connect_error(tungstenite::Error::Io(refused)),
+            ClientError::Connection(_)
+        ));
+
+        // Not about the device certificate
+        let alert = std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            rustls::Error::AlertReceived(rustls::AlertDescription::HandshakeFailure),
+        );
+        assert!(matches!(
+            connect_error(tungstenite::Error::Io(alert)),
+            ClientError::Connection(_)
+        ));
+
+    }
+
+    fn http_error(status: u16, retry_after: Option<&str>) -> ClientError {
+        let mut response = http::Response::builder().status(status);
+        if let Some(retry_after) = retry_after {
+            response = response.header(http::header::RETRY_AFTER, retry_after);
+        }
+        connect_error(tungstenite::Error::Http(response.body(None).unwrap()))
+    }
+
+    #[test]
+    fn classifies_upgrade_rejections() {
+        let err = http_error(429, Some("120"));
+        assert!(matches!(err, ClientError::RateLimited { .. }));
+        assert_eq!(err.retry_after(), Some(Duration::from_secs(120)));
+
+        let err = http_error(503, Some("30"));
+        assert!(matches!(err, ClientError::ServerError { status: 503, .. }));
+        assert_eq!(err.retry_after(), Some(Duration::from_secs(30)));
+
+        let err = http_error(502, Some("Wed, 21 Oct 2015 07:28:00 GMT"));
+        assert!(matches!(err, ClientError::ServerError { status: 502, .. }));
+        assert_eq!(err.retry_after(), None);
+
+        assert!(matches!(
+            http_error(400, None),
+            ClientError::Rejected { status: 400, .. }
+        ));
+        assert!(http_error(403, None).is_auth_failure());
+        assert!(!http_error(500, None).is_auth_failure());
+    }
+
+    fn join_reply(response: serde_json::Value) -> Message {
+        Message::from_json(
+            &json!(["1", "1", "device", "phx_reply", {"status": "error", "response": response}])
+                .to_string(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn classifies_join_rejections() {
+        assert!(matches!(
+            join_error(&join_reply(json!({"reason": "unauthorized"}))),
+            ClientError::Unauthorized(_)
+        ));
+        assert!(matches!(
+            join_error(&join_reply(json!({"reason": "Device not found"}))),
+            ClientError::DeviceUnknown(_)
+        ));
+        assert!(matches!(
+            join_error(&join_reply(json!({"reason": "invalid payload"}))),
+            ClientError::JoinRejected(_)
+        ));
+        // Reasons that merely contain one of the words are not classified
+        assert!(matches!(
+            join_error(&join_reply(json!({"reason": "unknown topic"}))),
+            ClientError::JoinRejected(_)
+        ));
+    }
+
+    #[test]
+    fn join_rejection_without_reason() {
+        let err = join_error(&join_reply(json!({})));
+        assert!(matches!(err, ClientError::JoinRejected(_)));
+        assert!(!err.is_auth_failure());
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_next_auth_method() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_config();
+        // Missing cert: mTLS fails locally, then shared secret is tried
+        // against a host that refuses connections.
+        config.hosts = vec!["127.0.0.1:1".to_string()];
+        config.auth.insert(
+            0,
+            AuthConfig::Mtls {
+                cert_path: dir.path().join("missing.pem"),
+                key_path: Some(dir.path().join("missing-key.pem")),
+                ca_cert_path: dir.path().join("ca.pem"),
+                sign_command: None,
+                pkcs12_password_file: None,
+            },
+        );
+        let client = NervesHubClient::new(config).unwrap();
+        let err = client.connect("127.0.0.1:1").await.unwrap_err();
+        assert!(matches!(err, ClientError::Connection(_)), "{err}");
+        assert!(client.status().snapshot().auth_method.is_none());
+    }
+
+    #[tokio::test]
+    async fn falls_back_when_server_rejects_handshake() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        // The server trusts one CA; the device certificate is from another
+        let ca = |name: &str| {
+            let key = rcgen::KeyPair::generate().unwrap();
+            let mut params = rcgen::CertificateParams::new(Vec::<String>::new()).unwrap();
+            params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+            params.distinguished_name.push(rcgen::DnType::CommonName, name);
+            let cert = params.self_signed(&key).unwrap();
+            (cert, key)
+        };
+        let (server_ca, server_ca_key) = ca("server ca");
+        let (other_ca, other_ca_key) = ca("other ca");
+        let server_key = rcgen::KeyPair::generate().unwrap();
+        let server_cert = rcgen::CertificateParams::new(vec!["127.0.0.1".to_string()])
+            .unwrap()
+            .signed_by(&server_key, &server_ca, &server_ca_key)
+            .unwrap();
+        let device_key = rcgen::KeyPair::generate().unwrap();
+        let device_cert = rcgen::CertificateParams::new(vec!["device".to_string()])
+            .unwrap()
+            .signed_by(&device_key, &other_ca, &other_ca_key)
+            .unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("ca.pem"), server_ca.pem()).unwrap();
+        std::fs::write(dir.path().join("cert.pem"), device_cert.pem()).unwrap();
+        std::fs::write(dir.path().join("key.pem"), device_key.serialize_pem()).unwrap();
+
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(server_ca.der().clone()).unwrap();
+        let verifier = rustls::server::WebPkiClientVerifier::builder(Arc::new(roots))
+            .build()
+            .unwrap();
+        let server_config = rustls::ServerConfig::builder()
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(
+                vec![server_cert.der().clone()],
+                rustls::pki_types::PrivateKeyDer::Pkcs8(server_key.serialize_der().into()),
+            )
+            .unwrap();
+        let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(server_config));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let host = listener.local_addr().unwrap().to_string();
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let counter = accepted.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                counter.fetch_add(1, Ordering::SeqCst);
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    let _ = acceptor.accept(stream).await;
+                });
+            }
+        });
+
+        let mtls = AuthConfig::Mtls {
+            cert_path: dir.path().join("cert.pem"),
+            key_path: Some(dir.path().join("key.pem")),
+            ca_cert_path: dir.path().join("ca.pem"),
+            sign_command: None,
+            pkcs12_password_file: None,
+        };
+        let mut config = test_config();
+        config.hosts = vec![host.clone()];
+        config.auth.insert(0, mtls.clone());
+        let client = NervesHubClient::new(config).unwrap();
+
+        let url = client.config.socket_url(&host).unwrap();
+        let err = client.connect_with(&mtls, &url).await.unwrap_err();
+        assert!(matches!(err, ClientError::Unauthorized(_)), "{err}");
+
+        // The shared secret method is tried next; it fails to verify the
+        // test server against the public roots
+        let err = client.connect(&host).await.unwrap_err();
+        assert!(matches!(err, ClientError::Connection(_)), "{err}");
+        assert_eq!(accepted.load(Ordering::SeqCst), 3);
+    }
+
+    /// Accept one WebSocket connection, answer the channel join and hand the
+    /// socket to `after_join`. Returns the `host:port` to connect to.
+    async fn stub_server<F, Fut>(after_join: F) -> String
+    where
+        F: FnOnce(tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>) -> Fut
+            + Send
+            + 'static,
+        Fut: std::future::Future<Output = ()> + Send,
+    {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let host = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+            let Some(Ok(tungstenite::Message::Text(text))) = ws.next().await else {
+                panic!("expected join");
+            };
+            let join = Message::from_json(&text).unwrap();
+            let reply = Message {
+                join_ref: join.join_ref.clone(),
+                msg_ref: join.msg_ref.clone(),
+                topic: join.topic,
+                event: "phx_reply".to_string(),
+                payload: json!({"status": "ok", "response": {}}),
+            };
+            ws.send(tungstenite::Message::Text(reply.to_json()))
+                .await
+                .unwrap();
+            after_join(ws).await;
+        });
+        host
+    }
+
+    fn plain_config(host: &str) -> Config {
+        let mut config = test_config();
+        config.hosts = vec![host.to_string()];
+        config.tls = Some(false);
+        config
+    }
+
+    #[tokio::test]
+    async fn pong_timeout_drops_connection() {
+        // Never reads after the join, so pings go unanswered
+        let host = stub_server(|ws| async move {
+            let _ws = ws;
+            tokio::time::sleep(Duration::from_secs(30)).await;
+        })
+        .await;
+        let mut config = plain_config(&host);
+        config.keepalive = Some(crate::config::KeepaliveConfig {
+            ping_interval_secs: Some(1),
+            pong_timeout_secs: Some(1),
+            ..Default::default()
+        });
+        let client = NervesHubClient::new(config).unwrap();
+        let (event_tx, _event_rx) = mpsc::channel(32);
+
+        let session = tokio::time::timeout(Duration::from_secs(10), client.run(&host, event_tx))
+            .await
+            .unwrap();
+        assert!(
+            matches!(session.result, Err(ClientError::PongTimeout(_))),
+            "{:?}",
+            session.result
+        );
+        assert!(session.joined_for.is_some());
+    }
+
+    #[tokio::test]
+    async fn answered_pings_keep_connection() {
+        // Reading lets tungstenite answer pings; close after a few
+        let host = stub_server(|mut ws| async move {
+            let mut pings = 0;
+            while let Some(Ok(msg)) = ws.next().await {
+                if msg.is_ping() {
+                    pings += 1;
+                    if pings == 3 {
+                        break;
+                    }
+                }
+            }
+            let _ = ws.close(None).await;
+        })
+        .await;
+        let mut config = plain_config(&host);
+        config.keepalive = Some(crate::config::KeepaliveConfig {
+            ping_interval_secs: Some(1),
+            pong_timeout_secs: Some(2),
+            ..Default::default()
+        });
+        let client = NervesHubClient::new(config).unwrap();
+        let (event_tx, _event_rx) = mpsc::channel(32);
+
+        let session = tokio::time::timeout(Duration::from_secs(10), client.run(&host, event_tx))
+            .await
+            .unwrap();
+        assert!(session.result.is_ok(), "{:?}", session.result);
+    }
+
+    #[tokio::test]
+    async fn progress_pushes_get_unique_refs() {
+        let (refs_tx, refs_rx) = tokio::sync::oneshot::channel();
+        let host = stub_server(|mut ws| async move {
+            let mut refs = Vec::new();
+            while let Some(Ok(msg)) = ws.next().await {
+                if let tungstenite::Message::Text(text) = msg {
+                    let msg = Message::from_json(&text).unwrap();
+                    if msg.event == "fwup_progress" {
+                        refs.push(msg.msg_ref.unwrap().parse::<u64>().unwrap());
+                        if refs.len() == 3 {
+                            break;
+                        }
+                    }
+                }
+            }
+            let _ = refs_tx.send(refs);
+            let _ = ws.close(None).await;
+        })
+        .await;
+        let mut client = NervesHubClient::new(plain_config(&host)).unwrap();
+        // Stand in for a running update's progress
+        let (reports_tx, reports_rx) = mpsc::channel(8);
+        client.reports = Mutex::new(reports_rx);
+        for pct in [10, 20, 30] {
+            reports_tx.send(UpdateReport::Progress(pct)).await.unwrap();
+        }
+        let (event_tx, _event_rx) = mpsc::channel(32);
+
+        let session = tokio::time::timeout(Duration::from_secs(10), client.run(&host, event_tx))
+            .await
+            .unwrap();
+        assert!(session.result.is_ok(), "{:?}", session.result);
+
+        let refs = refs_rx.await.unwrap();
+        assert_eq!(refs.len(), 3);
+        assert!(refs.windows(2).all(|w| w[0] < w[1]), "{:?}", refs);
+    }
+
+    /// Stub server that sends an update with `firmware_meta` after the join
+    /// and hands back the first message the client sends in response.
+    async fn send_update(
+        firmware_meta: serde_json::Value,
+    ) -> (String, tokio::sync::oneshot::Receiver<Message>) {
+        let (reply_tx, reply_rx) = tokio::sync::oneshot::channel();
+        let host = stub_server(|mut ws| async move {
+            let update = Message {
+                join_ref: None,
+                msg_ref: None,
+                topic: "device".to_string(),
+                event: "update".to_string(),
+                payload: json!({
+                    "firmware_url": "http://127.0.0.1:1/fw.fw",
+                    "firmware_meta": firmware_meta
+                }),
+            };
+            ws.send(tungstenite::Message::Text(update.to_json()))
+                .await
+                .unwrap();
+            while let Some(Ok(msg)) = ws.next().await {
+                if let tungstenite::Message::Text(text) = msg {
+                    let _ = reply_tx.send(Message::from_json(&text).unwrap());
+                    break;
+                }
+            }
+            let _ = ws.close(None).await;
+        })
+        .await;
+        (host, reply_rx)
+    }
+
+    #[tokio::test]
+    async fn incompatible_update_is_reported() {
+        let (host, reply_rx) = send_update(json!({
+            "uuid": "other",
+            "version": "2.0.0",
+            "platform": "x86_64",
+            "architecture": "x86_64",
+            "product": "test-product"
+        }))
+        .await;
+        let client = NervesHubClient::new(plain_config(&host)).unwrap();
+        let (event_tx, _event_rx) = mpsc::channel(32);
+
+        let session = tokio::time::timeout(Duration::from_secs(10), client.run(&host, event_tx))
+            .await
+            .unwrap();
+        // Rejecting the update doesn't drop the connection
+        assert!(session.result.is_ok(), "{:?}", session.result);
+
+        let reply = reply_rx.await.unwrap();
+        assert_eq!(reply.event, "status_update");
+        assert_eq!(reply.payload["status"], "update-failed");
+        assert!(reply.payload["reason"]
+            .as_str()
+            .unwrap()
+            .contains("platform x86_64 does not match device rpi4"));
+    }
+
+    #[tokio::test]
+    async fn failed_update_keeps_connection() {
+        let dir = tempfile::tempdir().unwrap();
+        let (seen_tx, seen_rx) = tokio::sync::oneshot::channel();
+        let host = stub_server(|mut ws| async move {
+            let update = Message {
+                join_ref: None,
+                msg_ref: None,
+                topic: "device".to_string(),
+                event: "update".to_string(),
+                payload: json!({
+                    "firmware_url": "http://127.0.0.1:1/fw.fw",
+                    "firmware_meta": {
+                        "uuid": "fw-new",
+                        "version": "2.0.0",
+                        "platform": "rpi4",
+                        "architecture": "arm",
+                        "product": "test-product"
+                    }
+                }),
+            };
+            ws.send(tungstenite::Message::Text(update.to_json()))
+                .await
+                .unwrap();
+            // The update's report and a later heartbeat on the same connection
+            let mut status = None;
+            while let Some(Ok(msg)) = ws.next().await {
+                if let tungstenite::Message::Text(text) = msg {
+                    let msg = Message::from_json(&text).unwrap();
+                    match msg.event.as_str() {
+                        "status_update" => status = Some(msg.payload),
+                        "heartbeat" if status.is_some() => break,
+                        _ => {}
+                    }
+                }
+            }
+            let _ = seen_tx.send(status);
+            let _ = ws.close(None).await;
+        })
+        .await;
+        let mut config = plain_config(&host);
+        config.data_dir = Some(dir.path().to_path_buf());
+        config.fwup_devpath = Some(dir.path().join("missing-device").display().to_string());
+        config.heartbeat_interval_secs = Some(1);
+        let client = NervesHubClient::new(config).unwrap();
+        let (event_tx, _event_rx) = mpsc::channel(32);
+
+        let session = tokio::time::timeout(Duration::from_secs(10), client.run(&host, event_tx))
+            .await
+            .unwrap();
+        assert!(session.result.is_ok(), "{:?}", session.result);
+
+        let status = seen_rx.await.unwrap().unwrap();
+        assert_eq!(status["status"], "update-failed");
+        assert!(status["reason"].as_str().unwrap().contains("missing-device"));
+    }
+
+    #[tokio::test]
+    async fn pending_update_is_not_applied_again() {
+        let dir = tempfile::tempdir().unwrap();
+        StateStore::new(dir.path())
+            .begin("fw-new", "2.0.0", UpdatePhase::Applied)
+            .unwrap();
+        let (host, reply_rx) = send_update(json!({
+            "uuid": "fw-new",
+            "version": "2.0.0",
+            "platform": "rpi4",
+            "architecture": "arm",
+            "product": "test-product"
+        }))
+        .await;
+        let mut config = plain_config(&host);
+        config.data_dir = Some(dir.path().to_path_buf());
+        let client = NervesHubClient::new(config).unwrap();
+        let (event_tx, _event_rx) = mpsc::channel(32);
+
+        let session = tokio::time::timeout(Duration::from_secs(10), client.run(&host, event_tx))
+            .await
+            .unwrap();
+        assert!(session.result.is_ok(), "{:?}", session.result);
+
+        // Handled right away, without a download attempt
+        let reply = reply_rx.await.unwrap();
+        assert_eq!(reply.event, "status_update");
+        assert_eq!(reply.payload["status"], "update-handled");
+        assert!(!dir.path().join("firmware.fw").exists());
+    }
+
+    #[tokio::test]
+    async fn reports_outcome_after_reboot() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = StateStore::new(dir.path());
+        store
+            .save(&crate::state::UpdateState {
+                update: Some(crate::state::UpdateRecord {
+                    // The firmware test_config says is running
+                    uuid: "fw-uuid-123".to_string(),
+                    version: "1.0.0".to_string(),
+                    phase: UpdatePhase::AwaitingReboot,
+                    boot_id: "earlier-boot".to_string(),
+                    validator: None,
+                    total: None,
+                    delta: false,
+                    error: None,
+                }),
+            })
+            .unwrap();
+
+        let (reply_tx, reply_rx) = tokio::sync::oneshot::channel();
+        let host = stub_server(|mut ws| async move {
+            while let Some(Ok(msg)) = ws.next().await {
+                if let tungstenite::Message::Text(text) = msg {
+                    let _ = reply_tx.send(Message::from_json(&text).unwrap());
+                    break;
+                }
+            }
+            let _ = ws.close(None).await;
+        })
+        .await;
+        let mut config = plain_config(&host);
+        config.data_dir = Some(dir.path().to_path_buf());
+        let client = NervesHubClient::new(config).unwrap();
+        let (event_tx, _event_rx) = mpsc::channel(32);
+
+        let session = tokio::time::timeout(Duration::from_secs(10), client.run(&host, event_tx))
+            .await
+            .unwrap();
+        assert!(session.result.is_ok(), "{:?}", session.result);
+
+        let reply = reply_rx.await.unwrap();
+        assert_eq!(reply.event, "status_update");
+        assert_eq!(reply.payload["status"], "update-succeeded");
+        assert_eq!(reply.payload["uuid"], "fw-uuid-123");
+        // Reported once
+        assert_eq!(store.load().update, None);
+    }
+
+    #[tokio::test]
+    async fn watchdog_reverts_unvalidated_firmware() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = StateStore::new(dir.path());
+        store
+            .save(&crate::state::UpdateState {
+                update: Some(crate::state::UpdateRecord {
+                    uuid: "fw-uuid-123".to_string(),
+                    version: "1.0.0".to_string(),
+                    phase: UpdatePhase::Applied,
+                    boot_id: "earlier-boot".to_string(),
+                    validator: None,
+                    total: None,
+                    delta: false,
+                    error: None,
+                }),
+            })
+            .unwrap();
+        let reverted = dir.path().join("reverted");
+        let mut config = test_config();
+        config.data_dir = Some(dir.path().to_path_buf());
+        config.update = Some(crate::config::UpdateConfig {
+            validation_timeout_secs: Some(1),
+            revert_command: Some(format!("touch {}", reverted.display())),
+            ..Default::default()
+        });
+
+        // Booted into the new firmware but never reached the server
+        let client = NervesHubClient::new(config).unwrap();
+        assert_eq!(store.load().update.unwrap().phase, UpdatePhase::Booted);
+        let watchdog = client.validation_watchdog().unwrap();
+        tokio::time::timeout(Duration::from_secs(5), watchdog)
+            .await
+            .unwrap();
+
+        assert!(reverted.exists());
+        let update = store.load().update.unwrap();
+        assert_eq!(update.phase, UpdatePhase::Failed);
+        assert!(update.error.unwrap().contains("not validated within 1s"));
+    }
 }
@@ -1,6 +1,14 @@
-use serde::Deserialize;
+use serde::de::{self, IntoDeserializer};
+use serde::{Deserialize, Deserializer};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::marker::PhantomData;
+use std::net::{IpAddr, SocketAddr};
 use std::path::PathBuf;
 use thiserror::Error;
+use url::Url;
+
+const DEFAULT_SOCKET_PATH: &str = "/device-socket/websocket";
 
 #[derive(Debug, Error)]
 pub enum ConfigError {
@@ -10,6 +18,8 @@ pub enum ConfigError {
     Parse(#[from] toml::de::Error),
     #[error("missing required field: {0}")]
     Missing(&'static str),
+    #[error("invalid config: {0}")]
+    Invalid(String),
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -17,8 +27,12 @@ pub enum ConfigError {
 pub enum AuthConfig {
     Mtls {
         cert_path: PathBuf,
-        key_path: PathBuf,
+        key_path: Option<PathBuf>,
         ca_cert_path: PathBuf,
+        /// External command that signs with a key not available as a file.
+        sign_command: Option<String>,
+        /// Password for a PKCS#12 bundle given as `cert_path`.
+        pkcs12_password_file: Option<PathBuf>,
     },
     SharedSecret {
         key: String,
@@ -26,6 +40,318 @@ pub enum AuthConfig {
     },
 }
 
+impl AuthConfig {
+    /// Short name of the method, as used in the config `type` field.
+    pub fn name(&self) -> &'static str {
+        match self {
+            AuthConfig::Mtls { .. } => "mtls",
+            AuthConfig::SharedSecret { .. } => "shared_secret",
+        }
+    }
+}
+
+/// Accept either a single `[auth]` table or an ordered `[[auth]]` list.
+fn deserialize_auth<'de, D>(deserializer: D) -> Result<Vec<AuthConfig>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserializer.deserialize_any(OneOrMany::new("an auth table or a list of them"))
+}
+
+/// Accept either a single `host` string or a `hosts` list.
+fn deserialize_hosts<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserializer.deserialize_any(OneOrMany::new("a host name or a list of them"))
+}
+
+/// Visitor for a single `T` or a list of them. Unlike an untagged enum it
+/// keeps the error from `T`, so a bad entry names the offending field.
+struct OneOrMany<T> {
+    expecting: &'static str,
+    marker: PhantomData<T>,
+}
+
+impl<T> OneOrMany<T> {
+    fn new(expecting: &'static str) -> Self {
+        Self {
+            expecting,
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<'de, T: Deserialize<'de>> de::Visitor<'de> for OneOrMany<T> {
+    type Value = Vec<T>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.expecting)
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<Self::Value, E> {
+        T::deserialize(value.into_deserializer()).map(|one| vec![one])
+    }
+
+    fn visit_map<A: de::MapAccess<'de>>(self, map: A) -> Result<Self::Value, A::Error> {
+        T::deserialize(de::value::MapAccessDeserializer::new(map)).map(|one| vec![one])
+    }
+
+    fn visit_seq<A: de::SeqAccess<'de>>(self, seq: A) -> Result<Self::Value, A::Error> {
+        Vec::deserialize(de::value::SeqAccessDeserializer::new(seq))
+    }
+}
+
+/// How the next host is picked when there are several.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Failover {
+    /// Always start from the first host, moving down the list on failure.
+    #[default]
+    Priority,
+    /// Rotate through the hosts, one per connection attempt.
+    RoundRobin,
+}
+
+/// Reconnect backoff policy.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ReconnectConfig {
+    pub min_delay_secs: Option<u64>,
+    pub max_delay_secs: Option<u64>,
+    /// Up to this fraction of the delay is added at random.
+    pub jitter: Option<f64>,
+    /// A connection joined for this long resets the backoff.
+    pub stable_after_secs: Option<u64>,
+    /// Minimum wait after the server refused the device.
+    pub auth_failure_delay_secs: Option<u64>,
+    /// Exit instead of retrying when the server refused the device.
+    pub exit_on_auth_failure: Option<bool>,
+}
+
+impl ReconnectConfig {
+    pub fn min_delay_secs(&self) -> u64 {
+        self.min_delay_secs.unwrap_or(1)
+    }
+
+    pub fn max_delay_secs(&self) -> u64 {
+        self.max_delay_secs.unwrap_or(60)
+    }
+
+    pub fn jitter(&self) -> f64 {
+        self.jitter.unwrap_or(0.5)
+    }
+
+    pub fn stable_after_secs(&self) -> u64 {
+        self.stable_after_secs.unwrap_or(60)
+    }
+
+    pub fn auth_failure_delay_secs(&self) -> u64 {
+        self.auth_failure_delay_secs.unwrap_or(3600)
+    }
+
+    pub fn exit_on_auth_failure(&self) -> bool {
+        self.exit_on_auth_failure.unwrap_or(false)
+    }
+}
+
+/// Connection liveness checks below the Phoenix heartbeat.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct KeepaliveConfig {
+    /// Send WebSocket ping frames this often. Disabled if unset.
+    pub ping_interval_secs: Option<u64>,
+    /// Drop the connection if no pong arrives within this time.
+    pub pong_timeout_secs: Option<u64>,
+    /// Enable TCP keepalive after this much idle time.
+    pub tcp_idle_secs: Option<u64>,
+    pub tcp_interval_secs: Option<u64>,
+    pub tcp_retries: Option<u32>,
+}
+
+impl KeepaliveConfig {
+    pub fn pong_timeout_secs(&self) -> u64 {
+        self.pong_timeout_secs.unwrap_or(10)
+    }
+}
+
+/// Which address family to try first when a host has both.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IpPreference {
+    /// Keep the resolver's order.
+    #[default]
+    System,
+    Ipv4,
+    Ipv6,
+}
+
+/// Where outgoing connections leave the device.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct BindConfig {
+    /// Network interface to send through (`SO_BINDTODEVICE`).
+    pub interface: Option<String>,
+    /// Local source address. Only servers of the same family are tried.
+    pub source_address: Option<IpAddr>,
+    pub ip_preference: Option<IpPreference>,
+}
+
+impl BindConfig {
+    pub fn ip_preference(&self) -> IpPreference {
+        self.ip_preference.unwrap_or_default()
+    }
+}
+
+/// Name resolution overrides.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct DnsConfig {
+    /// Fixed addresses per host name, used instead of DNS.
+    pub hosts: Option<BTreeMap<String, Vec<IpAddr>>>,
+    /// DNS servers (`ip` or `ip:port`) to query instead of the system ones.
+    pub servers: Option<Vec<String>>,
+}
+
+impl DnsConfig {
+    /// The configured DNS servers, port 53 unless given.
+    pub fn server_addrs(&self) -> Result<Vec<SocketAddr>, ConfigError> {
+        self.servers
+            .iter()
+            .flatten()
+            .map(|server| {
+                server
+                    .parse::<SocketAddr>()
+                    .or_else(|_| server.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, 53)))
+                    .map_err(|_| ConfigError::Invalid(format!("invalid dns server: {}", server)))
+            })
+            .collect()
+    }
+}
+
+/// Limits on firmware downloads.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct DownloadConfig {
+    /// Maximum download rate in bytes per second. Unlimited if unset.
+    pub max_bytes_per_sec: Option<u64>,
+    /// Local times of day (`HH:MM-HH:MM`) when downloading is allowed.
+    /// Always allowed if unset.
+    pub windows: Option<Vec<String>>,
+    /// Failed download attempts retried per update.
+    pub retries: Option<u32>,
+    pub retry_min_delay_secs: Option<u64>,
+    pub retry_max_delay_secs: Option<u64>,
+}
+
+impl DownloadConfig {
+    pub fn retries(&self) -> u32 {
+        self.retries.unwrap_or(3)
+    }
+
+    /// Backoff policy between download attempts.
+    pub fn retry_backoff(&self) -> ReconnectConfig {
+        ReconnectConfig {
+            min_delay_secs: Some(self.retry_min_delay_secs.unwrap_or(5)),
+            max_delay_secs: Some(self.retry_max_delay_secs.unwrap_or(60)),
+            ..Default::default()
+        }
+    }
+
+    pub fn windows(&self) -> Result<Vec<DownloadWindow>, ConfigError> {
+        self.windows
+            .iter()
+            .flatten()
+            .map(|window| {
+                DownloadWindow::parse(window).ok_or_else(|| {
+                    ConfigError::Invalid(format!("invalid download window: {}", window))
+                })
+            })
+            .collect()
+    }
+}
+
+/// Time of day range, in seconds since local midnight. Wraps past midnight
+/// when `end` is before `start`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DownloadWindow {
+    pub start: u32,
+    pub end: u32,
+}
+
+impl DownloadWindow {
+    fn parse(window: &str) -> Option<Self> {
+        let (start, end) = window.split_once('-')?;
+        let time = |s: &str| {
+            let (h, m) = s.trim().split_once(':')?;
+            let (h, m): (u32, u32) = (h.parse().ok()?, m.parse().ok()?);
+            (h < 24 && m < 60).then_some(h * 3600 + m * 60)
+        };
+        let (start, end) = (time(start)?, time(end)?);
+        (start != end).then_some(Self { start, end })
+    }
+
+    pub fn contains(&self, secs: u32) -> bool {
+        if self.start < self.end {
+            (self.start..self.end).contains(&secs)
+        } else {
+            secs >= self.start || secs < self.end
+        }
+    }
+
+    /// Seconds from `secs` until the window next opens.
+    pub fn secs_until_open(&self, secs: u32) -> u32 {
+        (self.start + 86400 - secs) % 86400
+    }
+}
+
+/// How firmware updates are accepted and applied.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct UpdateConfig {
+    /// Reject updates with an older version than the running firmware.
+    pub reject_downgrade: Option<bool>,
+    /// Command that marks new firmware as validated after its first join.
+    pub validate_command: Option<String>,
+    /// U-Boot environment key set to 1 with `fw_setenv` on validation.
+    pub validate_uboot_key: Option<String>,
+    /// Revert new firmware that isn't validated within this time.
+    pub validation_timeout_secs: Option<u64>,
+    /// Command that reverts to the previous firmware. `{devpath}` is
+    /// replaced with `fwup_devpath`.
+    pub revert_command: Option<String>,
+    /// Advertise and use delta updates.
+    pub delta: Option<bool>,
+}
+
+impl UpdateConfig {
+    pub fn reject_downgrade(&self) -> bool {
+        self.reject_downgrade.unwrap_or(false)
+    }
+
+    pub fn delta(&self) -> bool {
+        self.delta.unwrap_or(true)
+    }
+
+    pub fn revert_command(&self, devpath: &str) -> String {
+        self.revert_command
+            .as_deref()
+            .unwrap_or("fwup -a -d {devpath} -i /usr/share/fwup/revert.fw -t revert && reboot")
+            .replace("{devpath}", devpath)
+    }
+}
+
+/// Endpoint that signs device CSRs for the `provision` subcommand.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ProvisionConfig {
+    pub url: String,
+    /// Sent as a bearer token, if set.
+    pub token: Option<String>,
+}
+
+/// HTTP CONNECT proxy for the websocket and firmware downloads.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ProxyConfig {
+    /// `http://[user:password@]host[:port]`
+    pub url: String,
+    /// Hosts to connect to directly. Defaults to `NO_PROXY`.
+    pub no_proxy: Option<Vec<String>>,
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct FirmwareMetadata {
     pub uuid: String,
@@ -37,8 +363,20 @@ pub struct FirmwareMetadata {
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct Config {
-    pub host: String,
-    pub auth: AuthConfig,
+    /// Server hosts, in priority order. `host = "..."` sets a single one.
+    #[serde(alias = "host", deserialize_with = "deserialize_hosts")]
+    pub hosts: Vec<String>,
+    pub failover: Option<Failover>,
+    /// Server port, if not the scheme default.
+    pub port: Option<u16>,
+    pub socket_path: Option<String>,
+    /// Use `wss://` (default) or plain `ws://`.
+    pub tls: Option<bool>,
+    /// Extra query parameters for the socket URL, after `vsn`.
+    pub socket_params: Option<BTreeMap<String, String>>,
+    /// Authentication methods in the order they are tried.
+    #[serde(deserialize_with = "deserialize_auth")]
+    pub auth: Vec<AuthConfig>,
     pub serial_number_command: Option<String>,
     pub serial_number: Option<String>,
     pub fwup_devpath: Option<String>,
@@ -47,6 +385,21 @@ pub struct Config {
     pub heartbeat_interval_secs: Option<u64>,
     pub data_dir: Option<PathBuf>,
     pub device_api_version: Option<String>,
+    pub cert_expiry_warning_days: Option<i64>,
+    pub control_socket: Option<PathBuf>,
+    pub provision: Option<ProvisionConfig>,
+    pub reconnect: Option<ReconnectConfig>,
+    pub keepalive: Option<KeepaliveConfig>,
+    pub bind: Option<BindConfig>,
+    pub dns: Option<DnsConfig>,
+    pub download: Option<DownloadConfig>,
+    pub update: Option<UpdateConfig>,
+    /// Command whose success means the network is usable. Defaults to
+    /// checking for a default route.
+    pub connectivity_check_command: Option<String>,
+    pub connectivity_check_interval_secs: Option<u64>,
+    /// Proxy settings; without them `HTTPS_PROXY`/`NO_PROXY` are used.
+    pub proxy: Option<ProxyConfig>,
 }
 
 impl Config {
@@ -62,25 +415,156 @@ impl Config {
     }
 
     fn validate(&self) -> Result<(), ConfigError> {
-        if self.host.is_empty() {
+        if self.hosts.is_empty() || self.hosts.iter().any(|h| h.is_empty()) {
             return Err(ConfigError::Missing("host"));
         }
+        for host in &self.hosts {
+            self.build_socket_url(host)?;
+        }
+        if !self.tls() && self.auth.iter().any(|a| matches!(a, AuthConfig::Mtls { .. })) {
+            return Err(ConfigError::Invalid(
+                "mtls auth requires tls".to_string(),
+            ));
+        }
         if self.serial_number.is_none() && self.serial_number_command.is_none() {
             return Err(ConfigError::Missing(
                 "either serial_number or serial_number_command",
             ));
         }
+        if self.auth.is_empty() {
+            return Err(ConfigError::Missing("auth"));
+        }
+        for auth in &self.auth {
+            if let AuthConfig::Mtls {
+                key_path,
+                sign_command,
+                pkcs12_password_file,
+                ..
+            } = auth
+            {
+                match (key_path, sign_command) {
+                    // The key comes from the PKCS#12 bundle
+                    (None, None) if pkcs12_password_file.is_some() => {}
+                    (None, None) => {
+                        return Err(ConfigError::Missing("either key_path or sign_command"));
+                    }
+                    (Some(_), Some(_)) => {
+                        return Err(ConfigError::Invalid(
+                            "key_path and sign_command are mutually exclusive".to_string(),
+                        ));
+                    }
+                    _ => {}
+                }
+            }
+        }
+        let reconnect = self.reconnect();
+        let (min, max) = (reconnect.min_delay_secs(), reconnect.max_delay_secs());
+        if min == 0 || min > max {
+            return Err(ConfigError::Invalid(
+                "reconnect delays must satisfy 0 < min_delay_secs <= max_delay_secs".to_string(),
+            ));
+        }
+        if !(0.0..=1.0).contains(&reconnect.jitter()) {
+            return Err(ConfigError::Invalid(
+                "reconnect jitter must be between 0 and 1".to_string(),
+            ));
+        }
+        if self.keepalive().ping_interval_secs == Some(0) {
+            return Err(ConfigError::Invalid(
+                "keepalive ping_interval_secs must be positive".to_string(),
+            ));
+        }
+        if self.bind().interface.as_deref() == Some("") {
+            return Err(ConfigError::Invalid(
+                "bind interface must not be empty".to_string(),
+            ));
+        }
+        let dns = self.dns();
+        dns.server_addrs()?;
+        if let Some((host, _)) = dns.hosts.iter().flatten().find(|(_, ips)| ips.is_empty()) {
+            return Err(ConfigError::Invalid(format!(
+                "dns override for {} has no addresses",
+                host
+            )));
+        }
+        if self.update().validation_timeout_secs == Some(0) {
+            return Err(ConfigError::Invalid(
+                "update validation_timeout_secs must be positive".to_string(),
+            ));
+        }
+        let download = self.download();
+        if download.max_bytes_per_sec == Some(0) {
+            return Err(ConfigError::Invalid(
+                "download max_bytes_per_sec must be positive".to_string(),
+            ));
+        }
+        download.windows()?;
+        let retry = download.retry_backoff();
+        if retry.min_delay_secs() == 0 || retry.min_delay_secs() > retry.max_delay_secs() {
+            return Err(ConfigError::Invalid(
+                "download retry delays must satisfy 0 < retry_min_delay_secs <= retry_max_delay_secs".to_string(),
+            ));
+        }
+        if let Some(proxy) = &self.proxy {
+            crate::net::Proxy::parse(&proxy.url, Vec::new())
+                .map_err(|e| ConfigError::Invalid(e.to_string()))?;
+        }
         Ok(())
     }
 
-    pub fn socket_url(&self) -> String {
-        format!("wss://{}/device-socket/websocket?vsn=2.0.0", self.host)
+    /// WebSocket URL of the device socket on `host`. Checked for every
+    /// configured host when the config is loaded; other hosts may fail.
+    pub fn socket_url(&self, host: &str) -> Result<String, ConfigError> {
+        self.build_socket_url(host).map(String::from)
+    }
+
+    fn build_socket_url(&self, host: &str) -> Result<Url, ConfigError> {
+        let invalid = |reason: String| {
+            ConfigError::Invalid(format!("socket url for host {}: {}", host, reason))
+        };
+        let scheme = if self.tls() { "wss" } else { "ws" };
+        let mut url = Url::parse(&format!("{}://{}", scheme, host))
+            .map_err(|e| invalid(e.to_string()))?;
+        if url.host_str().is_none() || url.path() != "/" || url.query().is_some() {
+            return Err(invalid("host must be a hostname or address".to_string()));
+        }
+        if let Some(port) = self.port {
+            url.set_port(Some(port))
+                .map_err(|_| invalid("cannot set port".to_string()))?;
+        }
+        url.set_path(self.socket_path());
+        {
+            let mut query = url.query_pairs_mut();
+            query.append_pair("vsn", "2.0.0");
+            for (key, value) in self.socket_params.iter().flatten() {
+                query.append_pair(key, value);
+            }
+        }
+        Ok(url)
+    }
+
+    pub fn failover(&self) -> Failover {
+        self.failover.unwrap_or_default()
+    }
+
+    pub fn tls(&self) -> bool {
+        self.tls.unwrap_or(true)
+    }
+
+    pub fn socket_path(&self) -> &str {
+        self.socket_path.as_deref().unwrap_or(DEFAULT_SOCKET_PATH)
     }
 
     pub fn heartbeat_interval_secs(&self) -> u64 {
         self.heartbeat_interval_secs.unwrap_or(30)
     }
 
+    pub fn data_dir(&self) -> PathBuf {
+        self.data_dir
+            .clone()
+            .unwrap_or_else(|| PathBuf::from("/tmp/hub_link"))
+    }
+
     pub fn fwup_devpath(&self) -> &str {
         self.fwup_devpath.as_deref().unwrap_or("/dev/mmcblk0")
     }
@@ -92,6 +576,38 @@ impl Config {
     pub fn device_api_version(&self) -> &str {
         self.device_api_version.as_deref().unwrap_or("2.3.0")
     }
+
+    pub fn cert_expiry_warning_days(&self) -> i64 {
+        self.cert_expiry_warning_days.unwrap_or(30)
+    }
+
+    pub fn reconnect(&self) -> ReconnectConfig {
+        self.reconnect.clone().unwrap_or_default()
+    }
+
+    pub fn bind(&self) -> BindConfig {
+        self.bind.clone().unwrap_or_default()
+    }
+
+    pub fn update(&self) -> UpdateConfig {
+        self.update.clone().unwrap_or_default()
+    }
+
+    pub fn download(&self) -> DownloadConfig {
+        self.download.clone().unwrap_or_default()
+    }
+
+    pub fn dns(&self) -> DnsConfig {
+        self.dns.clone().unwrap_or_default()
+    }
+
+    pub fn keepalive(&self) -> KeepaliveConfig {
+        self.keepalive.clone().unwrap_or_default()
+    }
+
+    pub fn connectivity_check_interval_secs(&self) -> u64 {
+        self.connectivity_check_interval_secs.unwrap_or(5)
+    }
 }
 
 #[cfg(test)]
@@ -118,12 +634,14 @@ architecture = "arm"
 product = "my-product"
 "#;
         let config = Config::from_str(toml).unwrap();
-        assert_eq!(config.host, "devices.nerves-hub.org");
+        assert_eq!(config.hosts, vec!["devices.nerves-hub.org"]);
+        assert_eq!(config.failover(), Failover::Priority);
         assert_eq!(
-            config.socket_url(),
+            config.socket_url(&config.hosts[0]).unwrap(),
             "wss://devices.nerves-hub.org/device-socket/websocket?vsn=2.0.0"
         );
-        assert!(matches!(config.auth, AuthConfig::Mtls { .. }));
+        assert_eq!(config.auth.len(), 1);
+        assert!(matches!(config.auth[0], AuthConfig::Mtls { .. }));
         assert_eq!(config.firmware.uuid, "aaaa-bbbb");
     }
 
@@ -147,10 +665,160 @@ product = "my-product"
 "#;
         let config = Config::from_str(toml).unwrap();
         assert_eq!(
-            config.socket_url(),
+            config.socket_url(&config.hosts[0]).unwrap(),
             "wss://devices.nerves-hub.org/device-socket/websocket?vsn=2.0.0"
         );
-        assert!(matches!(config.auth, AuthConfig::SharedSecret { .. }));
+        assert!(matches!(config.auth[..], [AuthConfig::SharedSecret { .. }]));
+    }
+
+    #[test]
+    fn mtls_sign_command() {
+        let toml = r#"
+host = "devices.nerves-hub.org"
+serial_number = "device-1234"
+
+[auth]
+type = "mtls"
+cert_path = "/etc/hub_link/cert.pem"
+ca_cert_path = "/etc/hub_link/ca.pem"
+sign_command = "/usr/bin/se-sign"
+
+[firmware]
+uuid = "aaaa-bbbb"
+version = "1.0.0"
+platform = "rpi4"
+architecture = "arm"
+product = "my-product"
+"#;
+        let config = Config::from_str(toml).unwrap();
+        match &config.auth[0] {
+            AuthConfig::Mtls {
+                key_path,
+                sign_command,
+                ..
+            } => {
+                assert!(key_path.is_none());
+                assert_eq!(sign_command.as_deref(), Some("/usr/bin/se-sign"));
+            }
+            _ => panic!("expected mtls"),
+        }
+    }
+
+    #[test]
+    fn mtls_requires_one_key_source() {
+        let base = r#"
+host = "devices.nerves-hub.org"
+serial_number = "device-1234"
+
+[firmware]
+uuid = "u"
+version = "v"
+platform = "p"
+architecture = "a"
+product = "pr"
+
+[auth]
+type = "mtls"
+cert_path = "/etc/hub_link/cert.pem"
+ca_cert_path = "/etc/hub_link/ca.pem"
+"#;
+        assert!(matches!(
+            Config::from_str(base),
+            Err(ConfigError::Missing(_))
+        ));
+        let both = format!(
+            "{}key_path = \"/etc/hub_link/key.pem\"\nsign_command = \"sign\"\n",
+            base
+        );
+        assert!(matches!(
+            Config::from_str(&both),
+            Err(ConfigError::Invalid(_))
+        ));
+        let pkcs12 = format!(
+            "{}pkcs12_password_file = \"/etc/hub_link/p12.pass\"\n",
+            base
+        );
+        assert!(Config::from_str(&pkcs12).is_ok());
+    }
+
+    #[test]
+    fn ordered_auth_methods() {
+        let toml = r#"
+host = "devices.nerves-hub.org"
+serial_number = "device-1234"
+
+[[auth]]
+type = "mtls"
+cert_path = "/etc/hub_link/cert.pem"
+key_path = "/etc/hub_link/key.pem"
+ca_cert_path = "/etc/hub_link/ca.pem"
+
+[[auth]]
+type = "shared_secret"
+key = "my-key"
+secret = "super-secret"
+
+[firmware]
+uuid = "aaaa-bbbb"
+version = "1.0.0"
+platform = "rpi4"
+architecture = "arm"
+product = "my-product"
+"#;
+        let config = Config::from_str(toml).unwrap();
+        let names: Vec<&str> = config.auth.iter().map(|a| a.name()).collect();
+        assert_eq!(names, vec!["mtls", "shared_secret"]);
+    }
+
+    #[test]
+    fn bad_auth_entry_names_field() {
+        let base = r#"
+host = "example.com"
+serial_number = "dev-1"
+
+[firmware]
+uuid = "u"
+version = "v"
+platform = "p"
+architecture = "a"
+product = "pr"
+"#;
+        let table = format!("{}\n[auth]\ntype = \"shared_secret\"\nkey = \"k\"\n", base);
+        let err = Config::from_str(&table).unwrap_err().to_string();
+        assert!(err.contains("missing field `secret`"), "{}", err);
+
+        let list = format!(
+            "{}\n[[auth]]\ntype = \"shared_secret\"\nkey = \"k\"\nsecret = \"s\"\n\n[[auth]]\ntype = \"mtls\"\ncert_path = \"/c.pem\"\nkey_path = \"/k.pem\"\n",
+            base
+        );
+        let err = Config::from_str(&list).unwrap_err().to_string();
+        assert!(err.contains("missing field `ca_cert_path`"), "{}", err);
+
+        let hosts = base.replace("host = \"example.com\"", "hosts = [\"example.com\", 5]");
+        let table = format!("{}\n[auth]\ntype = \"shared_secret\"\nkey = \"k\"\nsecret = \"s\"\n", hosts);
+        let err = Config::from_str(&table).unwrap_err().to_string();
+        assert!(err.contains("invalid type: integer `5`"), "{}", err);
+        assert!(!err.contains("untagged"), "{}", err);
+    }
+
+    #[test]
+    fn empty_auth_list_fails() {
+        let toml = r#"
+host = "example.com"
+serial_number = "dev-1"
+auth = []
+
+[firmware]
+uuid = "u"
+version = "v"
+platform = "p"
+architecture = "a"
+product = "pr"
+"#;
+        assert!(matches!(
+            Config::from_str(toml),
+            Err(ConfigError::Missing("auth"))
+        ));
     }
 
     #[test]
@@ -217,5 +885,306 @@ product = "pr"
         assert_eq!(config.fwup_devpath(), "/dev/mmcblk0");
         assert_eq!(config.fwup_task(), "upgrade");
         assert_eq!(config.device_api_version(), "2.3.0");
+        assert_eq!(config.cert_expiry_warning_days(), 30);
+        assert!(config.control_socket.is_none());
+        assert_eq!(config.connectivity_check_interval_secs(), 5);
+        assert!(config.keepalive().ping_interval_secs.is_none());
+        assert_eq!(config.keepalive().pong_timeout_secs(), 10);
+    }
+
+    #[test]
+    fn proxy_config() {
+        let base = r#"
+host = "example.com"
+serial_number = "dev-1"
+
+[auth]
+type = "shared_secret"
+key = "k"
+secret = "s"
+
+[firmware]
+uuid = "u"
+version = "v"
+platform = "p"
+architecture = "a"
+product = "pr"
+"#;
+        let toml = format!(
+            "{}\n[proxy]\nurl = \"http://user:pw@proxy.local:3128\"\nno_proxy = [\"localhost\"]\n",
+            base
+        );
+        let config = Config::from_str(&toml).unwrap();
+        let proxy = config.proxy.unwrap();
+        assert_eq!(proxy.url, "http://user:pw@proxy.local:3128");
+        assert_eq!(proxy.no_proxy, Some(vec!["localhost".to_string()]));
+
+        let socks = format!("{}\n[proxy]\nurl = \"socks5://proxy.local\"\n", base);
+        assert!(matches!(
+            Config::from_str(&socks),
+            Err(ConfigError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn custom_socket_url() {
+        let toml = r#"
+host = "staging.example.com"
+port = 8443
+socket_path = "/socket/websocket"
+serial_number = "dev-1"
+
+[socket_params]
+region = "eu west"
+
+[auth]
+type = "shared_secret"
+key = "k"
+secret = "s"
+
+[firmware]
+uuid = "u"
+version = "v"
+platform = "p"
+architecture = "a"
+product = "pr"
+"#;
+        let config = Config::from_str(toml).unwrap();
+        assert_eq!(
+            config.socket_url(&config.hosts[0]).unwrap(),
+            "wss://staging.example.com:8443/socket/websocket?vsn=2.0.0&region=eu+west"
+        );
+
+        let plain = toml.replace("port = 8443", "tls = false\nport = 4000");
+        let config = Config::from_str(&plain).unwrap();
+        assert_eq!(
+            config.socket_url(&config.hosts[0]).unwrap(),
+            "ws://staging.example.com:4000/socket/websocket?vsn=2.0.0&region=eu+west"
+        );
+        assert!(matches!(
+            config.socket_url("staging.example.com/path"),
+            Err(ConfigError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn invalid_socket_url_fails() {
+        let base = r#"
+serial_number = "dev-1"
+
+[auth]
+type = "mtls"
+cert_path = "/etc/hub_link/cert.pem"
+key_path = "/etc/hub_link/key.pem"
+ca_cert_path = "/etc/hub_link/ca.pem"
+
+[firmware]
+uuid = "u"
+version = "v"
+platform = "p"
+architecture = "a"
+product = "pr"
+"#;
+        for host in ["exa mple.com", "example.com/path", "example.com?x=1"] {
+            let toml = format!("host = \"{}\"\n{}", host, base);
+            assert!(
+                matches!(Config::from_str(&toml), Err(ConfigError::Invalid(_))),
+                "{host}"
+            );
+        }
+
+        let no_tls = format!("host = \"example.com\"\ntls = false\n{}", base);
+        assert!(matches!(
+            Config::from_str(&no_tls),
+            Err(ConfigError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn multiple_hosts() {
+        let toml = r#"
+hosts = ["eu.example.com", "us.example.com:8443"]
+failover = "round_robin"
+serial_number = "dev-1"
+
+[auth]
+type = "shared_secret"
+key = "k"
+secret = "s"
+
+[firmware]
+uuid = "u"
+version = "v"
+platform = "p"
+architecture = "a"
+product = "pr"
+"#;
+        let config = Config::from_str(toml).unwrap();
+        assert_eq!(config.hosts, vec!["eu.example.com", "us.example.com:8443"]);
+        assert_eq!(config.failover(), Failover::RoundRobin);
+        assert_eq!(
+            config.socket_url("us.example.com:8443").unwrap(),
+            "wss://us.example.com:8443/device-socket/websocket?vsn=2.0.0"
+        );
+
+        let empty = toml.replace(r#"["eu.example.com", "us.example.com:8443"]"#, "[]");
+        assert!(matches!(
+            Config::from_str(&empty),
+            Err(ConfigError::Missing("host"))
+        ));
+    }
+
+    #[test]
+    fn reconnect_policy() {
+        let base = r#"
+host = "example.com"
+serial_number = "dev-1"
+
+[auth]
+type = "shared_secret"
+key = "k"
+secret = "s"
+
+[firmware]
+uuid = "u"
+version = "v"
+platform = "p"
+architecture = "a"
+product = "pr"
+"#;
+        let config = Config::from_str(base).unwrap();
+        assert_eq!(config.reconnect().min_delay_secs(), 1);
+        assert_eq!(config.reconnect().max_delay_secs(), 60);
+        assert_eq!(config.reconnect().stable_after_secs(), 60);
+        assert_eq!(config.reconnect().auth_failure_delay_secs(), 3600);
+        assert!(!config.reconnect().exit_on_auth_failure());
+
+        let toml = format!(
+            "{}\n[reconnect]\nmin_delay_secs = 2\nmax_delay_secs = 300\njitter = 0.2\nstable_after_secs = 120\n",
+            base
+        );
+        let reconnect = Config::from_str(&toml).unwrap().reconnect();
+        assert_eq!(reconnect.min_delay_secs(), 2);
+        assert_eq!(reconnect.max_delay_secs(), 300);
+        assert_eq!(reconnect.jitter(), 0.2);
+        assert_eq!(reconnect.stable_after_secs(), 120);
+
+        for bad in [
+            "min_delay_secs = 0",
+            "min_delay_secs = 90\nmax_delay_secs = 30",
+            "jitter = 1.5",
+        ] {
+            let toml = format!("{}\n[reconnect]\n{}\n", base, bad);
+            assert!(
+                matches!(Config::from_str(&toml), Err(ConfigError::Invalid(_))),
+                "{bad}"
+            );
+        }
+    }
+
+    #[test]
+    fn bind_config() {
+        let base = r#"
+host = "example.com"
+serial_number = "dev-1"
+
+[auth]
+type = "shared_secret"
+key = "k"
+secret = "s"
+
+[firmware]
+uuid = "u"
+version = "v"
+platform = "p"
+architecture = "a"
+product = "pr"
+"#;
+        assert_eq!(
+            Config::from_str(base).unwrap().bind().ip_preference(),
+            IpPreference::System
+        );
+
+        let toml = format!(
+            "{}\n[bind]\ninterface = \"wwan0\"\nsource_address = \"10.64.0.2\"\nip_preference = \"ipv4\"\n",
+            base
+        );
+        let bind = Config::from_str(&toml).unwrap().bind();
+        assert_eq!(bind.interface.as_deref(), Some("wwan0"));
+        assert_eq!(bind.source_address, Some("10.64.0.2".parse().unwrap()));
+        assert_eq!(bind.ip_preference(), IpPreference::Ipv4);
+
+        let bad = format!("{}\n[bind]\nsource_address = \"not-an-ip\"\n", base);
+        assert!(matches!(Config::from_str(&bad), Err(ConfigError::Parse(_))));
+    }
+
+    #[test]
+    fn dns_config() {
+        let base = r#"
+host = "example.com"
+serial_number = "dev-1"
+
+[auth]
+type = "shared_secret"
+key = "k"
+secret = "s"
+
+[firmware]
+uuid = "u"
+version = "v"
+platform = "p"
+architecture = "a"
+product = "pr"
+"#;
+        let toml = format!(
+            "{}\n[dns]\nservers = [\"10.0.0.1\", \"10.0.0.2:5353\"]\n\n[dns.hosts]\n\"example.com\" = [\"10.0.0.5\", \"fd00::5\"]\n",
+            base
+        );
+        let dns = Config::from_str(&toml).unwrap().dns();
+        assert_eq!(
+            dns.server_addrs().unwrap(),
+            vec![
+                "10.0.0.1:53".parse::<SocketAddr>().unwrap(),
+                "10.0.0.2:5353".parse().unwrap()
+            ]
+        );
+        assert_eq!(dns.hosts.unwrap()["example.com"].len(), 2);
+
+        for bad in [
+            "servers = [\"dns.example.com\"]",
+            "[dns.hosts]\n\"example.com\" = []",
+        ] {
+            let toml = format!("{}\n[dns]\n{}\n", base, bad);
+            assert!(
+                matches!(Config::from_str(&toml), Err(ConfigError::Invalid(_))),
+                "{bad}"
+            );
+        }
+    }
+
+    #[test]
+    fn download_windows() {
+        let download = DownloadConfig {
+            windows: Some(vec!["22:30-06:00".to_string(), "12:00-13:00".to_string()]),
+            ..Default::default()
+        };
+        let windows = download.windows().unwrap();
+        let night = windows[0];
+        assert_eq!(night, DownloadWindow { start: 81000, end: 21600 });
+        assert!(night.contains(23 * 3600));
+        assert!(night.contains(3600));
+        assert!(!night.contains(6 * 3600));
+        assert!(windows[1].contains(12 * 3600 + 59 * 60));
+        assert!(!windows[1].contains(13 * 3600));
+        assert_eq!(night.secs_until_open(22 * 3600), 1800);
+        assert_eq!(windows[1].secs_until_open(13 * 3600), 23 * 3600);
+
+        for bad in ["24:00-01:00", "10:00", "10:00-10:00", "ab:cd-01:00"] {
+            let download = DownloadConfig {
+                windows: Some(vec![bad.to_string()]),
+                ..Default::default()
+            };
+            assert!(download.windows().is_err(), "{bad}");
+        }
     }
 }
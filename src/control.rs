@@ -0,0 +1,166 @@
+use crate::firmware::DownloadControl;
+use crate::status::SharedStatus;
+use serde_json::json;
+use std::path::Path;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tracing::{debug, info, warn};
+
+/// Serve the control socket at `path`.
+///
+/// Clients send one command per line and get one JSON line back.
+/// Supported commands: `status`, `pause`/`resume` for firmware downloads,
+/// and `cancel` to abort the running firmware update.
+pub async fn serve(
+    path: &Path,
+    status: SharedStatus,
+    downloads: DownloadControl,
+) -> std::io::Result<()> {
+    // A stale socket from a previous run would make bind fail
+    if path.exists() {
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    info!(path = %path.display(), "control socket listening");
+
+    loop {
+        let (stream, _addr) = listener.accept().await?;
+        let status = status.clone();
+        let downloads = downloads.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, status, downloads).await {
+                debug!(error = %e, "control connection ended");
+            }
+        });
+    }
+}
+
+async fn handle_connection(
+    stream: UnixStream,
+    status: SharedStatus,
+    downloads: DownloadControl,
+) -> std::io::Result<()> {
+    let (read, mut write) = stream.into_split();
+    let mut lines = BufReader::new(read).lines();
+
+    while let Some(line) = lines.next_line().await? {
+        let reply = handle_command(line.trim(), &status, &downloads);
+        write.write_all(reply.to_string().as_bytes()).await?;
+        write.write_all(b"\n").await?;
+    }
+    Ok(())
+}
+
+fn handle_command(
+    command: &str,
+    status: &SharedStatus,
+    downloads: &DownloadControl,
+) -> serde_json::Value {
+    match command {
+        "status" => json!(status.snapshot()),
+        "pause" => {
+            info!("firmware downloads paused from control socket");
+            downloads.pause();
+            json!({"download_paused": true})
+        }
+        "resume" => {
+            info!("firmware downloads resumed from control socket");
+            downloads.resume();
+            json!({"download_paused": false})
+        }
+        "cancel" => {
+            let cancelled = downloads.cancel("cancelled from control socket");
+            if cancelled {
+                info!("firmware update cancelled from control socket");
+            }
+            json!({"cancelled": cancelled})
+        }
+        other => {
+            warn!(command = other, "unknown control command");
+            json!({"error": format!("unknown command: {}", other)})
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn request(path: &Path, command: &str) -> serde_json::Value {
+        let stream = UnixStream::connect(path).await.unwrap();
+        let (read, mut write) = stream.into_split();
+        write
+            .write_all(format!("{}\n", command).as_bytes())
+            .await
+            .unwrap();
+        let mut lines = BufReader::new(read).lines();
+        let line = lines.next_line().await.unwrap().unwrap();
+        serde_json::from_str(&line).unwrap()
+    }
+
+    async fn start(status: SharedStatus) -> (tempfile::TempDir, std::path::PathBuf) {
+        start_with(status, DownloadControl::new()).await
+    }
+
+    async fn start_with(
+        status: SharedStatus,
+        downloads: DownloadControl,
+    ) -> (tempfile::TempDir, std::path::PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("control.sock");
+        let serve_path = path.clone();
+        tokio::spawn(async move { serve(&serve_path, status, downloads).await });
+        while UnixStream::connect(&path).await.is_err() {
+            tokio::task::yield_now().await;
+        }
+        (dir, path)
+    }
+
+    #[tokio::test]
+    async fn status_command() {
+        let status = SharedStatus::new();
+        status.update(|s| s.cert_days_remaining = Some(42));
+        let (_dir, path) = start(status).await;
+
+        let reply = request(&path, "status").await;
+        assert_eq!(reply["connected"], false);
+        assert_eq!(reply["cert_days_remaining"], 42);
+    }
+
+    #[tokio::test]
+    async fn unknown_command() {
+        let (_dir, path) = start(SharedStatus::new()).await;
+        let reply = request(&path, "bogus").await;
+        assert!(reply["error"].as_str().unwrap().contains("bogus"));
+    }
+
+    #[tokio::test]
+    async fn pause_and_resume_downloads() {
+        let downloads = DownloadControl::new();
+        let (_dir, path) = start_with(SharedStatus::new(), downloads.clone()).await;
+
+        let reply = request(&path, "pause").await;
+        assert_eq!(reply["download_paused"], true);
+        assert!(downloads.is_paused());
+
+        let reply = request(&path, "resume").await;
+        assert_eq!(reply["download_paused"], false);
+        assert!(!downloads.is_paused());
+    }
+
+    #[tokio::test]
+    async fn cancel_update() {
+        let downloads = DownloadControl::new();
+        let (_dir, path) = start_with(SharedStatus::new(), downloads.clone()).await;
+
+        // Nothing to cancel
+        assert_eq!(request(&path, "cancel").await["cancelled"], false);
+
+        let cancelled = downloads.cancellation();
+        assert_eq!(request(&path, "cancel").await["cancelled"], true);
+        assert_eq!(
+            cancelled.borrow().as_deref(),
+            Some("cancelled from control socket")
+        );
+    }
+}
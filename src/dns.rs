@@ -0,0 +1,243 @@
+use crate::config::{BindConfig, ConfigError, DnsConfig, IpPreference};
+use hickory_resolver::config::{
+    LookupIpStrategy, NameServerConfig, Protocol, ResolverConfig, ResolverOpts,
+};
+use hickory_resolver::TokioAsyncResolver;
+use std::collections::BTreeMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How long to wait for a configured DNS server before trying the next.
+const NAMESERVER_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Resolves server names for the websocket and for reqwest, ordering the
+/// addresses by the configured IP preference.
+///
+/// Static overrides from `[dns.hosts]` win over DNS; otherwise the
+/// configured `[dns]` servers are queried, or the system resolver if none
+/// are set. Only the addresses change: TLS still uses the real host name.
+#[derive(Debug, Clone, Default)]
+pub struct Resolver {
+    preference: IpPreference,
+    /// Only addresses of this source address' family are usable.
+    source_address: Option<IpAddr>,
+    /// Lowercased host name to fixed addresses.
+    overrides: Arc<BTreeMap<String, Vec<IpAddr>>>,
+    nameservers: Option<TokioAsyncResolver>,
+}
+
+impl Resolver {
+    pub fn new(bind: &BindConfig, dns: &DnsConfig) -> Result<Self, ConfigError> {
+        let overrides = dns
+            .hosts
+            .iter()
+            .flatten()
+            .map(|(host, ips)| (host.to_ascii_lowercase(), ips.clone()))
+            .collect();
+        let servers = dns.server_addrs()?;
+        let nameservers = (!servers.is_empty()).then(|| {
+            let mut group = Vec::new();
+            for addr in servers {
+                group.push(NameServerConfig::new(addr, Protocol::Udp));
+                group.push(NameServerConfig::new(addr, Protocol::Tcp));
+            }
+            let config = ResolverConfig::from_parts(None, vec![], group);
+            let mut opts = ResolverOpts::default();
+            opts.timeout = NAMESERVER_TIMEOUT;
+            opts.attempts = 1;
+            // Both families; ordering is done here like for system lookups
+            opts.ip_strategy = LookupIpStrategy::Ipv4AndIpv6;
+            opts.use_hosts_file = false;
+            TokioAsyncResolver::tokio(config, opts)
+        });
+        Ok(Self {
+            preference: bind.ip_preference(),
+            source_address: bind.source_address,
+            overrides: Arc::new(overrides),
+            nameservers,
+        })
+    }
+
+    /// Resolve `host` to the addresses to try, in order.
+    pub async fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        let addrs = self.lookup(host, port).await?;
+        let addrs = self.order(addrs);
+        if addrs.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no usable address for {}", host),
+            ));
+        }
+        Ok(addrs)
+    }
+
+    async fn lookup(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        if let Some(ips) = self.overrides.get(&host.to_ascii_lowercase()) {
+            return Ok(ips.iter().map(|ip| SocketAddr::new(*ip, port)).collect());
+        }
+        let literal = host.trim_start_matches('[').trim_end_matches(']');
+        if let Ok(ip) = literal.parse::<IpAddr>() {
+            return Ok(vec![SocketAddr::new(ip, port)]);
+        }
+        match &self.nameservers {
+            Some(nameservers) => {
+                let lookup = nameservers
+                    .lookup_ip(host)
+                    .await
+                    .map_err(|e| io::Error::new(io::ErrorKind::NotFound, e))?;
+                Ok(lookup.iter().map(|ip| SocketAddr::new(ip, port)).collect())
+            }
+            None => Ok(tokio::net::lookup_host((host, port)).await?.collect()),
+        }
+    }
+
+    fn order(&self, mut addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+        if let Some(source) = self.source_address {
+            addrs.retain(|addr| addr.is_ipv4() == source.is_ipv4());
+        }
+        // Stable sort keeps the system order within a family
+        match self.preference {
+            IpPreference::System => {}
+            IpPreference::Ipv4 => addrs.sort_by_key(|addr| !addr.is_ipv4()),
+            IpPreference::Ipv6 => addrs.sort_by_key(|addr| !addr.is_ipv6()),
+        }
+        addrs
+    }
+}
+
+impl reqwest::dns::Resolve for Resolver {
+    fn resolve(&self, name: reqwest::dns::Name) -> reqwest::dns::Resolving {
+        let resolver = self.clone();
+        Box::pin(async move {
+            // reqwest fills in the port
+            let addrs = Resolver::resolve(&resolver, name.as_str(), 0).await?;
+            Ok(Box::new(addrs.into_iter()) as reqwest::dns::Addrs)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addrs() -> Vec<SocketAddr> {
+        vec![
+            "[2001:db8::1]:443".parse().unwrap(),
+            "192.0.2.1:443".parse().unwrap(),
+            "[2001:db8::2]:443".parse().unwrap(),
+            "192.0.2.2:443".parse().unwrap(),
+        ]
+    }
+
+    fn resolver(preference: IpPreference, source_address: Option<&str>) -> Resolver {
+        Resolver {
+            preference,
+            source_address: source_address.map(|addr| addr.parse().unwrap()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn orders_by_preference() {
+        let system = resolver(IpPreference::System, None).order(addrs());
+        assert_eq!(system, addrs());
+
+        let ipv4 = resolver(IpPreference::Ipv4, None).order(addrs());
+        let ips: Vec<String> = ipv4.iter().map(|a| a.ip().to_string()).collect();
+        assert_eq!(ips, ["192.0.2.1", "192.0.2.2", "2001:db8::1", "2001:db8::2"]);
+
+        let ipv6 = resolver(IpPreference::Ipv6, None).order(addrs());
+        assert!(ipv6[0].is_ipv6() && ipv6[1].is_ipv6());
+    }
+
+    #[test]
+    fn source_address_limits_family() {
+        let ordered = resolver(IpPreference::System, Some("10.0.0.5")).order(addrs());
+        assert_eq!(ordered.len(), 2);
+        assert!(ordered.iter().all(|addr| addr.is_ipv4()));
+    }
+
+    #[tokio::test]
+    async fn resolves_literals() {
+        let resolver = resolver(IpPreference::System, Some("::1"));
+        let addrs = resolver.resolve("::1", 80).await.unwrap();
+        assert_eq!(addrs, vec!["[::1]:80".parse().unwrap()]);
+
+        let err = resolver.resolve("127.0.0.1", 80).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[tokio::test]
+    async fn overrides_take_precedence() {
+        let dns = DnsConfig {
+            hosts: Some(BTreeMap::from([(
+                "Hub.Example.com".to_string(),
+                vec!["10.0.0.5".parse().unwrap(), "fd00::5".parse().unwrap()],
+            )])),
+            servers: None,
+        };
+        let bind = BindConfig {
+            ip_preference: Some(IpPreference::Ipv6),
+            ..Default::default()
+        };
+        let resolver = Resolver::new(&bind, &dns).unwrap();
+        let addrs = resolver.resolve("hub.example.com", 443).await.unwrap();
+        assert_eq!(
+            addrs,
+            vec![
+                "[fd00::5]:443".parse::<SocketAddr>().unwrap(),
+                "10.0.0.5:443".parse().unwrap()
+            ]
+        );
+    }
+
+    /// Answer A queries for any name with 192.0.2.7 and AAAA with nothing.
+    async fn stub_nameserver() -> SocketAddr {
+        let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            loop {
+                let Ok((_, peer)) = socket.recv_from(&mut buf).await else {
+                    return;
+                };
+                // Header is 12 bytes, then the question: name, type, class
+                let mut end = 12;
+                while buf[end] != 0 {
+                    end += buf[end] as usize + 1;
+                }
+                let question = &buf[12..end + 5];
+                let qtype = u16::from_be_bytes([buf[end + 1], buf[end + 2]]);
+                let answers: u16 = if qtype == 1 { 1 } else { 0 };
+
+                let mut reply = Vec::new();
+                reply.extend_from_slice(&buf[..2]);
+                reply.extend_from_slice(&[0x81, 0x80, 0, 1]);
+                reply.extend_from_slice(&answers.to_be_bytes());
+                reply.extend_from_slice(&[0, 0, 0, 0]);
+                reply.extend_from_slice(question);
+                if answers > 0 {
+                    // Name pointer to the question, A, IN, TTL 60, 4 bytes
+                    reply.extend_from_slice(&[0xc0, 0x0c, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4]);
+                    reply.extend_from_slice(&[192, 0, 2, 7]);
+                }
+                let _ = socket.send_to(&reply, peer).await;
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn queries_configured_servers() {
+        let server = stub_nameserver().await;
+        let dns = DnsConfig {
+            hosts: None,
+            servers: Some(vec![server.to_string()]),
+        };
+        let resolver = Resolver::new(&BindConfig::default(), &dns).unwrap();
+        let addrs = resolver.resolve("hub.example.com", 443).await.unwrap();
+        assert_eq!(addrs, vec!["192.0.2.7:443".parse::<SocketAddr>().unwrap()]);
+    }
+}
@@ -0,0 +1,100 @@
+use crate::config::Failover;
+
+/// Picks the server host for each connection attempt.
+#[derive(Debug)]
+pub struct HostSelector {
+    hosts: Vec<String>,
+    strategy: Failover,
+    next: usize,
+    /// Consecutive failures since the last successful connection.
+    failures: usize,
+}
+
+impl HostSelector {
+    pub fn new(hosts: Vec<String>, strategy: Failover) -> Self {
+        assert!(!hosts.is_empty(), "at least one host is required");
+        Self {
+            hosts,
+            strategy,
+            next: 0,
+            failures: 0,
+        }
+    }
+
+    /// Host for the next connection attempt.
+    pub fn current(&self) -> &str {
+        &self.hosts[self.next]
+    }
+
+    /// The connection on the current host ended cleanly.
+    pub fn record_success(&mut self) {
+        self.failures = 0;
+        self.next = match self.strategy {
+            Failover::Priority => 0,
+            Failover::RoundRobin => (self.next + 1) % self.hosts.len(),
+        };
+    }
+
+    /// The connection on the current host failed; move to the next one.
+    ///
+    /// Returns `true` once every host has failed in a row, meaning the caller
+    /// should back off before starting over.
+    pub fn record_failure(&mut self) -> bool {
+        self.failures += 1;
+        self.next = (self.next + 1) % self.hosts.len();
+        if self.failures < self.hosts.len() {
+            return false;
+        }
+        self.failures = 0;
+        if self.strategy == Failover::Priority {
+            self.next = 0;
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hosts() -> Vec<String> {
+        vec!["a".to_string(), "b".to_string(), "c".to_string()]
+    }
+
+    #[test]
+    fn priority_returns_to_first_host() {
+        let mut selector = HostSelector::new(hosts(), Failover::Priority);
+        assert_eq!(selector.current(), "a");
+        assert!(!selector.record_failure());
+        assert_eq!(selector.current(), "b");
+        selector.record_success();
+        assert_eq!(selector.current(), "a");
+
+        assert!(!selector.record_failure());
+        assert!(!selector.record_failure());
+        assert_eq!(selector.current(), "c");
+        // All three failed: back off and start over from the primary
+        assert!(selector.record_failure());
+        assert_eq!(selector.current(), "a");
+    }
+
+    #[test]
+    fn round_robin_rotates() {
+        let mut selector = HostSelector::new(hosts(), Failover::RoundRobin);
+        selector.record_success();
+        assert_eq!(selector.current(), "b");
+        assert!(!selector.record_failure());
+        assert_eq!(selector.current(), "c");
+        assert!(!selector.record_failure());
+        assert!(selector.record_failure());
+        assert_eq!(selector.current(), "b");
+    }
+
+    #[test]
+    fn single_host_backs_off_every_failure() {
+        let mut selector = HostSelector::new(vec!["a".to_string()], Failover::Priority);
+        assert!(selector.record_failure());
+        assert!(selector.record_failure());
+        assert_eq!(selector.current(), "a");
+    }
+}
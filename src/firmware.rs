@@ -1,29 +1,79 @@
+use crate::backoff::Backoff;
+use crate::config::{DownloadConfig, DownloadWindow, FirmwareMetadata};
 use serde::Deserialize;
 use serde_json::Value;
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileTypeExt;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use thiserror::Error;
+use tokio::sync::watch;
 use tracing::{info, warn};
 
+/// fwup program, looked up in `PATH`.
+pub const FWUP: &str = "fwup";
+
+/// Delta download in the data directory, next to the full image.
+pub const DELTA_FILE: &str = "delta.fw";
+
+/// Longest sleep while waiting for a download window, so clock changes
+/// are picked up.
+const WINDOW_RECHECK: Duration = Duration::from_secs(60);
+
 #[derive(Debug, Error)]
 pub enum FirmwareError {
     #[error("download failed: {0}")]
     Download(String),
+    #[error("download failed: HTTP {0}")]
+    HttpStatus(u16),
     #[error("fwup failed: {0}")]
     Fwup(String),
+    #[error("pre-flight check failed: {0}")]
+    Preflight(String),
+    #[error("incompatible firmware: {0}")]
+    Incompatible(String),
     #[error("invalid update message: {0}")]
     InvalidMessage(String),
     #[error("io error: {0}")]
     Io(#[from] std::io::Error),
 }
 
+impl FirmwareError {
+    /// Whether another download attempt may succeed.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            FirmwareError::Download(_) => true,
+            FirmwareError::HttpStatus(status) => {
+                *status >= 500 || *status == 408 || *status == 429
+            }
+            _ => false,
+        }
+    }
+}
+
 /// Parsed firmware update info from the server's "update" event.
 #[derive(Debug, Clone, Deserialize)]
 pub struct UpdateInfo {
     pub firmware_url: String,
     pub firmware_meta: FirmwareMeta,
+    /// Patch against earlier firmware, for devices that advertise delta
+    /// support. `firmware_url` stays the full image to fall back to.
+    #[serde(default)]
+    pub delta: Option<DeltaUpdate>,
+}
+
+/// A delta firmware file: fwup rebuilds the full image from the running
+/// firmware's partitions and the patches in it.
+#[derive(Debug, Clone, Deserialize)]
+pub struct DeltaUpdate {
+    pub firmware_url: String,
+    /// UUID of the firmware the patch applies to.
+    pub source_uuid: String,
 }
 
-#[allow(dead_code)]
 #[derive(Debug, Clone, Deserialize)]
 pub struct FirmwareMeta {
     pub uuid: String,
@@ -39,62 +89,548 @@ impl UpdateInfo {
         serde_json::from_value(payload.clone())
             .map_err(|e| FirmwareError::InvalidMessage(e.to_string()))
     }
+
+    /// The delta to download instead of the full image, if there is one
+    /// for the firmware that is running.
+    pub fn delta_for(&self, running_uuid: &str) -> Option<&DeltaUpdate> {
+        self.delta
+            .as_ref()
+            .filter(|delta| delta.source_uuid == running_uuid)
+    }
+
+    /// Check that the update is built for this device: platform,
+    /// architecture and product must match the running firmware. With
+    /// `reject_downgrade`, the version must not be older either.
+    pub fn check_compatible(
+        &self,
+        current: &FirmwareMetadata,
+        reject_downgrade: bool,
+    ) -> Result<(), FirmwareError> {
+        let meta = &self.firmware_meta;
+        for (field, update, running) in [
+            ("platform", &meta.platform, &current.platform),
+            ("architecture", &meta.architecture, &current.architecture),
+            ("product", &meta.product, &current.product),
+        ] {
+            if update != running {
+                return Err(FirmwareError::Incompatible(format!(
+                    "{} {} does not match device {}",
+                    field, update, running
+                )));
+            }
+        }
+        if reject_downgrade {
+            let parse = |version: &str| {
+                semver::Version::parse(version).map_err(|e| {
+                    FirmwareError::Incompatible(format!(
+                        "cannot compare version {}: {}",
+                        version, e
+                    ))
+                })
+            };
+            if parse(&meta.version)? < parse(&current.version)? {
+                return Err(FirmwareError::Incompatible(format!(
+                    "version {} is older than running {}",
+                    meta.version, current.version
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Pause switch and cancellation for firmware downloads, shared with the
+/// control socket.
+#[derive(Debug, Clone)]
+pub struct DownloadControl {
+    paused: Arc<watch::Sender<bool>>,
+    /// Reason the running update should stop, if asked to.
+    cancel: Arc<watch::Sender<Option<String>>>,
+}
+
+impl Default for DownloadControl {
+    fn default() -> Self {
+        Self {
+            paused: Arc::new(watch::channel(false).0),
+            cancel: Arc::new(watch::channel(None).0),
+        }
+    }
+}
+
+impl DownloadControl {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn pause(&self) {
+        self.paused.send_replace(true);
+    }
+
+    pub fn resume(&self) {
+        self.paused.send_replace(false);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        *self.paused.borrow()
+    }
+
+    /// Ask the running update to stop. Returns `false` if no update can be
+    /// cancelled right now.
+    pub fn cancel(&self, reason: &str) -> bool {
+        if self.cancel.receiver_count() == 0 {
+            return false;
+        }
+        self.cancel.send_replace(Some(reason.to_string()));
+        true
+    }
+
+    /// Make an update cancellable for as long as the receiver is held.
+    /// Earlier cancel requests don't carry over.
+    pub fn cancellation(&self) -> watch::Receiver<Option<String>> {
+        self.cancel.send_replace(None);
+        self.cancel.subscribe()
+    }
+
+    /// Like [`Self::cancellation`], but a pending request stays in effect:
+    /// for an update queued behind one that is being cancelled, until it is
+    /// [withdrawn](Self::withdraw), or one that becomes cancellable again.
+    pub fn pending_cancellation(&self) -> watch::Receiver<Option<String>> {
+        self.cancel.subscribe()
+    }
+
+    /// Cancel the running update for one that replaces it. Unlike
+    /// [`Self::cancel`] the request is kept even if the update can't be
+    /// cancelled right now, so it stops before its next download. Returns
+    /// whether the update was cancellable.
+    pub fn supersede(&self, reason: &str) -> bool {
+        let cancellable = self.cancel.receiver_count() > 0;
+        self.cancel.send_replace(Some(reason.to_string()));
+        cancellable
+    }
+
+    /// Withdraw the cancel request `reason` unless a newer one replaced it.
+    pub fn withdraw(&self, reason: &str) {
+        self.cancel.send_if_modified(|current| {
+            let withdrawn = current.as_deref() == Some(reason);
+            if withdrawn {
+                *current = None;
+            }
+            withdrawn
+        });
+    }
+
+    /// Wait until no update is cancellable any more.
+    pub async fn settled(&self) {
+        self.cancel.closed().await
+    }
+}
+
+/// Rate limit, download windows and pause switch applied to a download.
+#[derive(Debug, Clone, Default)]
+pub struct Throttle {
+    max_bytes_per_sec: Option<u64>,
+    windows: Vec<DownloadWindow>,
+    control: DownloadControl,
+}
+
+impl Throttle {
+    pub fn new(config: &DownloadConfig, control: DownloadControl) -> Self {
+        Self {
+            max_bytes_per_sec: config.max_bytes_per_sec,
+            // Checked when the config was loaded
+            windows: config.windows().unwrap_or_default(),
+            control,
+        }
+    }
+
+    /// Wait while downloads are paused or outside every window. Returns
+    /// whether it had to wait.
+    async fn wait_allowed(&self) -> bool {
+        let mut waited = false;
+        loop {
+            let mut changes = self.control.paused.subscribe();
+            let wait = if self.control.is_paused() {
+                if !waited {
+                    info!("firmware download paused");
+                }
+                None
+            } else {
+                match self.until_window(local_secs_of_day()) {
+                    None => {
+                        if waited {
+                            info!("firmware download resumed");
+                        }
+                        return waited;
+                    }
+                    Some(wait) => {
+                        if !waited {
+                            info!(wait_secs = wait.as_secs(), "outside download window, waiting");
+                        }
+                        Some(wait.min(WINDOW_RECHECK))
+                    }
+                }
+            };
+            waited = true;
+            // Recheck when the pause switch flips or the window may be open
+            match wait {
+                Some(wait) => {
+                    tokio::select! {
+                        _ = changes.changed() => {}
+                        _ = tokio::time::sleep(wait) => {}
+                    }
+                }
+                None => {
+                    let _ = changes.changed().await;
+                }
+            }
+        }
+    }
+
+    /// Time until a download window opens, or `None` if downloading is
+    /// allowed now.
+    fn until_window(&self, now: u32) -> Option<Duration> {
+        if self.windows.is_empty() || self.windows.iter().any(|w| w.contains(now)) {
+            return None;
+        }
+        let secs = self.windows.iter().map(|w| w.secs_until_open(now)).min()?;
+        Some(Duration::from_secs(secs.into()))
+    }
+
+    /// How long to sleep so that `bytes` read over `elapsed` stay within
+    /// the rate limit.
+    fn rate_delay(&self, bytes: u64, elapsed: Duration) -> Option<Duration> {
+        let rate = self.max_bytes_per_sec?;
+        let due = Duration::from_secs_f64(bytes as f64 / rate as f64);
+        due.checked_sub(elapsed).filter(|delay| !delay.is_zero())
+    }
+}
+
+/// Seconds since local midnight.
+fn local_secs_of_day() -> u32 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as libc::time_t;
+    // SAFETY: tm is plain data; all-zero is a valid value
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    // SAFETY: both pointers are valid for the duration of the call
+    if unsafe { libc::localtime_r(&now, &mut tm) }.is_null() {
+        return (now % 86400) as u32;
+    }
+    (tm.tm_hour * 3600 + tm.tm_min * 60 + tm.tm_sec) as u32
+}
+
+/// A firmware download that can continue where a failed attempt stopped.
+///
+/// A retry asks for the remaining bytes with `Range`, guarded by `If-Range`
+/// so a file that changed on the server is downloaded again from the start
+/// instead of being spliced together.
+#[derive(Debug)]
+pub struct Download {
+    url: String,
+    path: PathBuf,
+    downloaded: u64,
+    total: Option<u64>,
+    /// ETag or Last-Modified of the partial file, needed to resume it.
+    validator: Option<String>,
+    /// Whether the last attempt was held back mid-transfer, during which
+    /// the server may have dropped the idle connection.
+    held_back: bool,
 }
 
-/// Download firmware from a pre-signed URL to a local file.
+impl Download {
+    pub fn new(url: &str, dest_dir: &Path) -> Self {
+        Self::with_path(url, dest_dir.join("firmware.fw"))
+    }
+
+    /// Download a delta firmware file, kept apart from the full image.
+    pub fn delta(url: &str, dest_dir: &Path) -> Self {
+        Self::with_path(url, dest_dir.join(DELTA_FILE))
+    }
+
+    fn with_path(url: &str, path: PathBuf) -> Self {
+        Self {
+            url: url.to_string(),
+            path,
+            downloaded: 0,
+            total: None,
+            validator: None,
+            held_back: false,
+        }
+    }
+
+    /// Continue a download that an earlier run of hub_link left at this
+    /// path, identified by `validator`. The URL may differ, e.g. a freshly
+    /// signed one for the same file.
+    pub fn resume(mut self, validator: Option<String>, total: Option<u64>) -> Self {
+        let partial = std::fs::metadata(&self.path).map_or(0, |m| m.len());
+        // A complete (or oversized) file can't be ranged; start over
+        if validator.is_some() && total.is_some_and(|total| partial < total) {
+            self.downloaded = partial;
+            self.total = total;
+            self.validator = validator;
+        }
+        self
+    }
+
+    /// Where the firmware is downloaded to.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn downloaded(&self) -> u64 {
+        self.downloaded
+    }
+
+    pub fn total(&self) -> Option<u64> {
+        self.total
+    }
+
+    pub fn validator(&self) -> Option<&str> {
+        self.validator.as_deref()
+    }
+
+    /// Download the rest of the file.
+    /// Reports progress via a callback that gets the download after every
+    /// chunk, to read the progress and checkpoint the resume validator.
+    /// No request is sent while `throttle` holds the download back, and
+    /// reading stops while it does mid-transfer; the server is then slowed
+    /// down by TCP flow control.
+    pub async fn run<F>(
+        &mut self,
+        client: &reqwest::Client,
+        throttle: &Throttle,
+        mut on_progress: F,
+    ) -> Result<(), FirmwareError>
+    where
+        F: FnMut(&Download),
+    {
+        self.held_back = false;
+        throttle.wait_allowed().await;
+        let mut request = client.get(&self.url);
+        let resume_from = match &self.validator {
+            Some(validator) if self.downloaded > 0 => {
+                request = request
+                    .header(reqwest::header::RANGE, format!("bytes={}-", self.downloaded))
+                    .header(reqwest::header::IF_RANGE, validator);
+                Some(self.downloaded)
+            }
+            _ => None,
+        };
+        let response = request
+            .send()
+            .await
+            .map_err(|e| FirmwareError::Download(e.to_string()))?;
+
+        let status = response.status();
+        let resumed = status == reqwest::StatusCode::PARTIAL_CONTENT
+            && resume_from.is_some()
+            && content_range_start(&response) == resume_from;
+        if !status.is_success() || (status == reqwest::StatusCode::PARTIAL_CONTENT && !resumed) {
+            return Err(FirmwareError::HttpStatus(status.as_u16()));
+        }
+
+        use futures_util::StreamExt;
+        use tokio::io::AsyncWriteExt;
+
+        let needed = if resumed {
+            self.total.map(|total| total.saturating_sub(self.downloaded))
+        } else {
+            // Starting over overwrites the partial file, freeing its space
+            response
+                .content_length()
+                .map(|len| len.saturating_sub(self.downloaded))
+        };
+        if let Some(needed) = needed {
+            check_free_space(self.path.parent().unwrap_or(Path::new(".")), needed)?;
+        }
+
+        let mut file = if resumed {
+            info!(offset = self.downloaded, "resuming firmware download");
+            tokio::fs::OpenOptions::new()
+                .append(true)
+                .open(&self.path)
+                .await
+                .map_err(FirmwareError::Io)?
+        } else {
+            if resume_from.is_some() {
+                info!("firmware changed on the server, downloading from the start");
+            }
+            self.downloaded = 0;
+            self.total = response.content_length();
+            self.validator = validator(&response);
+            tokio::fs::File::create(&self.path)
+                .await
+                .map_err(FirmwareError::Io)?
+        };
+        let total_size = self.total;
+        let mut stream = response.bytes_stream();
+
+        // Rate is measured from the last pause so a long wait doesn't allow a burst
+        let mut rate_start = Instant::now();
+        let mut rate_bytes: u64 = 0;
+        loop {
+            if throttle.wait_allowed().await {
+                self.held_back = true;
+                rate_start = Instant::now();
+                rate_bytes = 0;
+            }
+            let Some(chunk) = stream.next().await else {
+                break;
+            };
+            let chunk = chunk.map_err(|e| FirmwareError::Download(e.to_string()))?;
+            file.write_all(&chunk)
+                .await
+                .map_err(FirmwareError::Io)?;
+            // Counted once written, so a resume continues from the file end
+            self.downloaded += chunk.len() as u64;
+            on_progress(self);
+
+            rate_bytes += chunk.len() as u64;
+            if let Some(delay) = throttle.rate_delay(rate_bytes, rate_start.elapsed()) {
+                tokio::time::sleep(delay).await;
+            }
+        }
+
+        file.flush().await.map_err(FirmwareError::Io)?;
+        if total_size.is_some_and(|total| self.downloaded < total) {
+            return Err(FirmwareError::Download(format!(
+                "connection closed after {} of {} bytes",
+                self.downloaded,
+                total_size.unwrap_or_default()
+            )));
+        }
+        info!(downloaded_bytes = self.downloaded, path = %self.path.display(), "firmware download complete");
+        Ok(())
+    }
+}
+
+/// Validator identifying this version of the file, for `If-Range`.
+fn validator(response: &reqwest::Response) -> Option<String> {
+    let headers = response.headers();
+    headers
+        .get(reqwest::header::ETAG)
+        .filter(|etag| !etag.as_bytes().starts_with(b"W/"))
+        .or_else(|| headers.get(reqwest::header::LAST_MODIFIED))
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string)
+}
+
+/// First byte of a `Content-Range: bytes start-end/total` response.
+fn content_range_start(response: &reqwest::Response) -> Option<u64> {
+    let range = response.headers().get(reqwest::header::CONTENT_RANGE)?;
+    let range = range.to_str().ok()?.strip_prefix("bytes ")?;
+    range.split_once('-')?.0.trim().parse().ok()
+}
+
+/// Download firmware from a pre-signed URL to a local file, retrying failed
+/// attempts with backoff. Each retry continues where the previous attempt
+/// stopped when the server allows it.
 /// Returns the path to the downloaded file.
-/// Reports progress via a callback: fn(bytes_downloaded, total_bytes_option).
 pub async fn download_firmware<F>(
-    url: &str,
-    dest_dir: &Path,
+    client: &reqwest::Client,
+    mut download: Download,
+    throttle: &Throttle,
+    retries: u32,
+    mut backoff: Backoff,
     mut on_progress: F,
 ) -> Result<PathBuf, FirmwareError>
 where
-    F: FnMut(u64, Option<u64>),
+    F: FnMut(&Download),
 {
-    let client = reqwest::Client::new();
-    let response = client
-        .get(url)
-        .send()
-        .await
-        .map_err(|e| FirmwareError::Download(e.to_string()))?;
+    let mut attempt = 0;
+    loop {
+        match download.run(client, throttle, &mut on_progress).await {
+            Ok(()) => return Ok(download.path),
+            // Not the server's fault: reconnect without using up a retry
+            Err(e) if download.held_back && e.is_retryable() => {
+                info!(error = %e, "firmware download interrupted while held back, reconnecting");
+            }
+            Err(e) if attempt < retries && e.is_retryable() => {
+                attempt += 1;
+                let delay = backoff.delay();
+                warn!(
+                    error = %e,
+                    attempt,
+                    retries,
+                    delay_ms = delay.as_millis() as u64,
+                    "firmware download failed, retrying"
+                );
+                tokio::time::sleep(delay).await;
+                backoff.escalate();
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Checks before downloading, so an update that cannot be applied fails
+/// early: `fwup` runs and the target device exists. Returns the fwup version.
+pub async fn preflight(fwup: &str, devpath: &str) -> Result<String, FirmwareError> {
+    check_devpath(Path::new(devpath))?;
+    let version = fwup_version(fwup).await?;
+    info!(fwup_version = %version, devpath, "pre-flight checks passed");
+    Ok(version)
+}
 
-    if !response.status().is_success() {
-        return Err(FirmwareError::Download(format!(
-            "HTTP {}",
-            response.status()
+/// The target must be a block device, or a regular file for image builds.
+fn check_devpath(devpath: &Path) -> Result<(), FirmwareError> {
+    let metadata = std::fs::metadata(devpath).map_err(|e| {
+        FirmwareError::Preflight(format!("fwup devpath {}: {}", devpath.display(), e))
+    })?;
+    let file_type = metadata.file_type();
+    if !file_type.is_block_device() && !file_type.is_file() {
+        return Err(FirmwareError::Preflight(format!(
+            "fwup devpath {} is not a block device or file",
+            devpath.display()
         )));
     }
+    Ok(())
+}
 
-    let total_size = response.content_length();
-    let dest_path = dest_dir.join("firmware.fw");
-    let mut file = tokio::fs::File::create(&dest_path)
+async fn fwup_version(program: &str) -> Result<String, FirmwareError> {
+    let output = tokio::process::Command::new(program)
+        .arg("--version")
+        .output()
         .await
-        .map_err(|e| FirmwareError::Io(e))?;
-
-    let mut downloaded: u64 = 0;
-    let mut stream = response.bytes_stream();
-
-    use futures_util::StreamExt;
-    use tokio::io::AsyncWriteExt;
-
-    while let Some(chunk) = stream.next().await {
-        let chunk = chunk.map_err(|e| FirmwareError::Download(e.to_string()))?;
-        file.write_all(&chunk)
-            .await
-            .map_err(|e| FirmwareError::Io(e))?;
-        downloaded += chunk.len() as u64;
-        on_progress(downloaded, total_size);
+        .map_err(|e| FirmwareError::Preflight(format!("cannot run {}: {}", program, e)))?;
+    if !output.status.success() {
+        return Err(FirmwareError::Preflight(format!(
+            "{} --version exit {}",
+            program, output.status
+        )));
     }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
 
-    file.flush().await.map_err(|e| FirmwareError::Io(e))?;
-    info!(downloaded_bytes = downloaded, path = %dest_path.display(), "firmware download complete");
+/// Fail unless the filesystem holding `dir` has `needed` bytes available.
+fn check_free_space(dir: &Path, needed: u64) -> Result<(), FirmwareError> {
+    let available = available_space(dir)?;
+    if available < needed {
+        return Err(FirmwareError::Preflight(format!(
+            "not enough space in {}: {} bytes needed, {} available",
+            dir.display(),
+            needed,
+            available
+        )));
+    }
+    Ok(())
+}
 
-    Ok(dest_path)
+fn available_space(dir: &Path) -> io::Result<u64> {
+    let path = CString::new(dir.as_os_str().as_bytes())?;
+    // SAFETY: statvfs is plain data; all-zero is a valid value
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    // SAFETY: path is NUL-terminated and stat is valid for writes
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
 }
 
-/// Apply firmware using the fwup CLI tool.
+/// Apply firmware using the `fwup` CLI tool.
 pub async fn apply_firmware(
+    fwup: &str,
     firmware_path: &Path,
     devpath: &str,
     task: &str,
@@ -106,7 +642,7 @@ pub async fn apply_firmware(
         "applying firmware with fwup"
     );
 
-    let output = tokio::process::Command::new("fwup")
+    let output = tokio::process::Command::new(fwup)
         .arg("-a")
         .arg("-d")
         .arg(devpath)
@@ -144,6 +680,123 @@ pub fn progress_percent(downloaded: u64, total: Option<u64>) -> u8 {
 mod tests {
     use super::*;
     use serde_json::json;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use std::sync::Mutex;
+    use tokio::net::TcpListener;
+
+    /// Firmware file server for download tests.
+    struct Stub {
+        body: Vec<u8>,
+        /// Sent as ETag; ranges are only honored for a matching If-Range.
+        etag: Option<&'static str>,
+        /// Close the connection after this many body bytes, once per entry.
+        cuts: Vec<usize>,
+        /// Status of the first response instead of the body.
+        fail_status: Option<u16>,
+    }
+
+    impl Stub {
+        fn new(body: Vec<u8>) -> Self {
+            Self {
+                body,
+                etag: Some("\"v1\""),
+                cuts: Vec::new(),
+                fail_status: None,
+            }
+        }
+
+        /// Serve over HTTP in small writes. Returns the URL and the
+        /// request heads received.
+        async fn serve(mut self) -> (String, Arc<Mutex<Vec<String>>>) {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("http://{}/fw.fw", listener.local_addr().unwrap());
+            let requests = Arc::new(Mutex::new(Vec::new()));
+            let seen = requests.clone();
+            tokio::spawn(async move {
+                self.cuts.reverse();
+                loop {
+                    let (mut stream, _) = listener.accept().await.unwrap();
+                    let mut request = Vec::new();
+                    let mut buf = [0u8; 1024];
+                    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                        let n = stream.read(&mut buf).await.unwrap();
+                        request.extend_from_slice(&buf[..n]);
+                    }
+                    let head = String::from_utf8_lossy(&request).to_lowercase();
+                    seen.lock().unwrap().push(head.clone());
+
+                    if let Some(status) = self.fail_status.take() {
+                        let reply = format!(
+                            "HTTP/1.1 {} Error\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                            status
+                        );
+                        stream.write_all(reply.as_bytes()).await.unwrap();
+                        continue;
+                    }
+
+                    let range_start = head
+                        .lines()
+                        .find_map(|l| l.strip_prefix("range: bytes=")?.strip_suffix('-')?.parse().ok());
+                    let if_range = head
+                        .lines()
+                        .find_map(|l| l.strip_prefix("if-range: ").map(str::to_string));
+                    let etag = self.etag.map(str::to_lowercase);
+                    let start = range_start.filter(|_| if_range.is_some() && if_range == etag);
+
+                    let total = self.body.len();
+                    let mut reply = match start {
+                        Some(start) => format!(
+                            "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes {}-{}/{}\r\nContent-Length: {}\r\n",
+                            start,
+                            total - 1,
+                            total,
+                            total - start
+                        ),
+                        None => format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n", total),
+                    };
+                    if let Some(etag) = self.etag {
+                        reply.push_str(&format!("ETag: {}\r\n", etag));
+                    }
+                    reply.push_str("Connection: close\r\n\r\n");
+                    stream.write_all(reply.as_bytes()).await.unwrap();
+
+                    let body = &self.body[start.unwrap_or(0)..];
+                    let body = match self.cuts.pop() {
+                        Some(cut) => &body[..cut],
+                        None => body,
+                    };
+                    for chunk in body.chunks(1000) {
+                        if stream.write_all(chunk).await.is_err() {
+                            break;
+                        }
+                        let _ = stream.flush().await;
+                    }
+                }
+            });
+            (url, requests)
+        }
+    }
+
+    fn body(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn no_delay() -> Backoff {
+        Backoff::new(&crate::config::ReconnectConfig {
+            min_delay_secs: Some(0),
+            jitter: Some(0.0),
+            ..Default::default()
+        })
+    }
+
+    fn throttle(max_bytes_per_sec: Option<u64>, windows: &[&str]) -> Throttle {
+        let config = DownloadConfig {
+            max_bytes_per_sec,
+            windows: Some(windows.iter().map(|w| w.to_string()).collect()),
+            ..Default::default()
+        };
+        Throttle::new(&config, DownloadControl::new())
+    }
 
     #[test]
     fn parse_update_info() {
@@ -162,6 +815,34 @@ mod tests {
         assert_eq!(info.firmware_meta.uuid, "abc-123");
         assert_eq!(info.firmware_meta.version, "1.1.0");
         assert_eq!(info.firmware_meta.platform, "rpi4");
+        assert!(info.delta_for("abc-100").is_none());
+    }
+
+    #[test]
+    fn parse_delta_update() {
+        let payload = json!({
+            "firmware_url": "https://s3.example.com/full.fw",
+            "firmware_meta": {
+                "uuid": "abc-123",
+                "version": "1.1.0",
+                "platform": "rpi4",
+                "architecture": "arm",
+                "product": "my-product"
+            },
+            "delta": {
+                "firmware_url": "https://s3.example.com/delta.fw",
+                "source_uuid": "abc-100"
+            }
+        });
+        let info = UpdateInfo::from_payload(&payload).unwrap();
+        let delta = info.delta_for("abc-100").unwrap();
+        assert_eq!(delta.firmware_url, "https://s3.example.com/delta.fw");
+        // Built against other firmware: the full image is needed
+        assert!(info.delta_for("abc-099").is_none());
+
+        let dir = Path::new("/data");
+        assert_eq!(Download::delta(&delta.firmware_url, dir).path(), dir.join("delta.fw"));
+        assert_eq!(Download::new(&info.firmware_url, dir).path(), dir.join("firmware.fw"));
     }
 
     #[test]
@@ -179,4 +860,413 @@ mod tests {
         assert_eq!(progress_percent(50, None), 0);
         assert_eq!(progress_percent(50, Some(0)), 0);
     }
+
+    #[test]
+    fn window_wait() {
+        let throttle = throttle(None, &["01:00-05:00", "12:00-13:00"]);
+        assert_eq!(throttle.until_window(2 * 3600), None);
+        assert_eq!(
+            throttle.until_window(6 * 3600),
+            Some(Duration::from_secs(6 * 3600))
+        );
+        assert_eq!(
+            throttle.until_window(14 * 3600),
+            Some(Duration::from_secs(11 * 3600))
+        );
+        assert_eq!(Throttle::default().until_window(0), None);
+    }
+
+    #[test]
+    fn rate_delay() {
+        let throttle = throttle(Some(1000), &[]);
+        assert_eq!(
+            throttle.rate_delay(2000, Duration::from_millis(500)),
+            Some(Duration::from_millis(1500))
+        );
+        assert_eq!(throttle.rate_delay(2000, Duration::from_secs(3)), None);
+        assert_eq!(Throttle::default().rate_delay(2000, Duration::ZERO), None);
+    }
+
+    #[tokio::test]
+    async fn download_respects_rate_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let (url, _) = Stub::new(vec![7u8; 20_000]).serve().await;
+        let throttle = throttle(Some(50_000), &[]);
+
+        let started = Instant::now();
+        let mut last = (0, None);
+        let path = download_firmware(
+            &reqwest::Client::new(),
+            Download::new(&url, dir.path()),
+            &throttle,
+            0,
+            no_delay(),
+            |d| last = (d.downloaded(), d.total()),
+        )
+        .await
+        .unwrap();
+        // 20 kB at 50 kB/s
+        assert!(started.elapsed() >= Duration::from_millis(350));
+        assert_eq!(last, (20_000, Some(20_000)));
+        assert_eq!(std::fs::read(path).unwrap().len(), 20_000);
+    }
+
+    #[tokio::test]
+    async fn pause_holds_download() {
+        let dir = tempfile::tempdir().unwrap();
+        let (url, _) = Stub::new(vec![7u8; 5_000]).serve().await;
+        let control = DownloadControl::new();
+        let throttle = Throttle::new(&DownloadConfig::default(), control.clone());
+        control.pause();
+
+        let dest = dir.path().to_path_buf();
+        let download = tokio::spawn(async move {
+            let client = reqwest::Client::new();
+            download_firmware(&client, Download::new(&url, &dest), &throttle, 0, no_delay(), |_| {}).await
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!download.is_finished());
+
+        control.resume();
+        tokio::time::timeout(Duration::from_secs(5), download)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn paused_download_waits_before_requesting() {
+        let dir = tempfile::tempdir().unwrap();
+        let (url, requests) = Stub::new(body(5_000)).serve().await;
+        let control = DownloadControl::new();
+        let throttle = Throttle::new(&DownloadConfig::default(), control.clone());
+        control.pause();
+
+        let dest = dir.path().to_path_buf();
+        let download = tokio::spawn(async move {
+            let client = reqwest::Client::new();
+            download_firmware(&client, Download::new(&url, &dest), &throttle, 0, no_delay(), |_| {}).await
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(requests.lock().unwrap().is_empty());
+
+        control.resume();
+        let path = tokio::time::timeout(Duration::from_secs(5), download)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(std::fs::read(path).unwrap(), body(5_000));
+        assert_eq!(requests.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn reset_after_pause_is_not_a_retry() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut stub = Stub::new(body(10_000));
+        stub.cuts = vec![2_000];
+        let (url, requests) = stub.serve().await;
+        let control = DownloadControl::new();
+        let throttle = Throttle::new(&DownloadConfig::default(), control.clone());
+
+        // Pause after the first chunk, as if an operator did
+        let mut paused = false;
+        let path = download_firmware(
+            &reqwest::Client::new(),
+            Download::new(&url, dir.path()),
+            &throttle,
+            0,
+            no_delay(),
+            |_| {
+                if !paused {
+                    paused = true;
+                    control.pause();
+                    let control = control.clone();
+                    tokio::spawn(async move {
+                        tokio::time::sleep(Duration::from_millis(50)).await;
+                        control.resume();
+                    });
+                }
+            },
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(std::fs::read(path).unwrap(), body(10_000));
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert!(requests[1].contains("range: bytes=2000-"));
+    }
+
+    #[tokio::test]
+    async fn cancel_needs_running_update() {
+        let control = DownloadControl::new();
+        assert!(!control.cancel("too early"));
+
+        let mut cancelled = control.cancellation();
+        // The earlier request doesn't cancel the new update
+        assert_eq!(*cancelled.borrow_and_update(), None);
+        assert!(control.cancel("operator"));
+        let reason = cancelled.wait_for(Option::is_some).await.unwrap().clone();
+        assert_eq!(reason.as_deref(), Some("operator"));
+
+        drop(cancelled);
+        tokio::time::timeout(Duration::from_secs(1), control.settled())
+            .await
+            .unwrap();
+        assert!(!control.cancel("too late"));
+    }
+
+    #[tokio::test]
+    async fn queued_update_keeps_newer_cancel() {
+        let control = DownloadControl::new();
+        let _running = control.cancellation();
+        assert!(control.cancel("superseded"));
+
+        // The superseding request only applies to the running update
+        let queued = control.pending_cancellation();
+        control.withdraw("superseded");
+        assert_eq!(*queued.borrow(), None);
+
+        // A request made meanwhile is kept for the queued one
+        assert!(control.supersede("superseded"));
+        assert!(control.cancel("operator"));
+        control.withdraw("superseded");
+        assert_eq!(queued.borrow().as_deref(), Some("operator"));
+    }
+
+    #[tokio::test]
+    async fn retry_resumes_with_range() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut stub = Stub::new(body(10_000));
+        stub.cuts = vec![4_000];
+        let (url, requests) = stub.serve().await;
+
+        let mut progress = Vec::new();
+        let path = download_firmware(
+            &reqwest::Client::new(),
+            Download::new(&url, dir.path()),
+            &Throttle::default(),
+            3,
+            no_delay(),
+            |d| progress.push((d.downloaded(), d.total())),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(std::fs::read(path).unwrap(), body(10_000));
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert!(requests[1].contains("range: bytes=4000-"));
+        assert!(requests[1].contains("if-range: \"v1\""));
+        // Progress continues from the resume point against the full size
+        assert!(progress.windows(2).all(|w| w[0].0 < w[1].0));
+        assert_eq!(progress.last(), Some(&(10_000, Some(10_000))));
+    }
+
+    #[tokio::test]
+    async fn retry_restarts_without_validator() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut stub = Stub::new(body(10_000));
+        stub.etag = None;
+        stub.cuts = vec![4_000, 2_000];
+        let (url, requests) = stub.serve().await;
+
+        let path = download_firmware(
+            &reqwest::Client::new(),
+            Download::new(&url, dir.path()),
+            &Throttle::default(),
+            3,
+            no_delay(),
+            |_| {},
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(std::fs::read(path).unwrap(), body(10_000));
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 3);
+        assert!(requests.iter().all(|r| !r.contains("range:")));
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_retries() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut stub = Stub::new(body(10_000));
+        stub.cuts = vec![1_000, 1_000, 1_000];
+        let (url, requests) = stub.serve().await;
+
+        let err = download_firmware(
+            &reqwest::Client::new(),
+            Download::new(&url, dir.path()),
+            &Throttle::default(),
+            2,
+            no_delay(),
+            |_| {},
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(err, FirmwareError::Download(_)));
+        assert_eq!(requests.lock().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn client_errors_are_not_retried() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut stub = Stub::new(body(100));
+        stub.fail_status = Some(403);
+        let (url, requests) = stub.serve().await;
+
+        let err = download_firmware(
+            &reqwest::Client::new(),
+            Download::new(&url, dir.path()),
+            &Throttle::default(),
+            3,
+            no_delay(),
+            |_| {},
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(err, FirmwareError::HttpStatus(403)));
+        assert_eq!(requests.lock().unwrap().len(), 1);
+        assert!(FirmwareError::HttpStatus(503).is_retryable());
+    }
+
+    #[test]
+    fn devpath_checks() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("image.img");
+        std::fs::write(&image, "").unwrap();
+        check_devpath(&image).unwrap();
+
+        for bad in [dir.path().to_path_buf(), dir.path().join("missing")] {
+            let err = check_devpath(&bad).unwrap_err();
+            assert!(matches!(err, FirmwareError::Preflight(_)), "{}", bad.display());
+        }
+    }
+
+    #[tokio::test]
+    async fn missing_fwup_fails() {
+        let err = fwup_version("hub-link-no-such-fwup").await.unwrap_err();
+        assert!(err.to_string().contains("cannot run hub-link-no-such-fwup"));
+    }
+
+    #[tokio::test]
+    async fn free_space_checked_before_writing() {
+        let dir = tempfile::tempdir().unwrap();
+        check_free_space(dir.path(), 1).unwrap();
+        assert!(matches!(
+            check_free_space(dir.path(), u64::MAX),
+            Err(FirmwareError::Preflight(_))
+        ));
+
+        // The stub claims a huge body; nothing is written before failing
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/fw.fw", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 1024];
+            let _ = stream.read(&mut buf).await;
+            let reply = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", u64::MAX / 2);
+            let _ = stream.write_all(reply.as_bytes()).await;
+        });
+        let err = download_firmware(
+            &reqwest::Client::new(),
+            Download::new(&url, dir.path()),
+            &Throttle::default(),
+            3,
+            no_delay(),
+            |_| {},
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(err, FirmwareError::Preflight(_)));
+        assert!(!dir.path().join("firmware.fw").exists());
+    }
+
+    fn running() -> FirmwareMetadata {
+        FirmwareMetadata {
+            uuid: "current".to_string(),
+            version: "1.2.0".to_string(),
+            platform: "rpi4".to_string(),
+            architecture: "arm".to_string(),
+            product: "my-product".to_string(),
+        }
+    }
+
+    fn update(platform: &str, version: &str) -> UpdateInfo {
+        UpdateInfo::from_payload(&json!({
+            "firmware_url": "https://example.com/fw.fw",
+            "firmware_meta": {
+                "uuid": "new",
+                "version": version,
+                "platform": platform,
+                "architecture": "arm",
+                "product": "my-product"
+            }
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn rejects_other_platform() {
+        update("rpi4", "1.3.0").check_compatible(&running(), false).unwrap();
+        let err = update("x86_64", "1.3.0")
+            .check_compatible(&running(), false)
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "incompatible firmware: platform x86_64 does not match device rpi4"
+        );
+    }
+
+    #[test]
+    fn downgrade_protection() {
+        // Allowed unless protection is on
+        update("rpi4", "1.1.9").check_compatible(&running(), false).unwrap();
+        let err = update("rpi4", "1.1.9")
+            .check_compatible(&running(), true)
+            .unwrap_err();
+        assert!(err.to_string().contains("older than running 1.2.0"));
+
+        update("rpi4", "1.2.0").check_compatible(&running(), true).unwrap();
+        update("rpi4", "1.10.0").check_compatible(&running(), true).unwrap();
+        assert!(matches!(
+            update("rpi4", "1.3.0-rc.1").check_compatible(&running(), true),
+            Ok(())
+        ));
+        assert!(matches!(
+            update("rpi4", "latest").check_compatible(&running(), true),
+            Err(FirmwareError::Incompatible(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn resumes_download_from_earlier_run() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("firmware.fw"), &body(10_000)[..4_000]).unwrap();
+        let (url, requests) = Stub::new(body(10_000)).serve().await;
+
+        let download = Download::new(&url, dir.path()).resume(Some("\"v1\"".to_string()), Some(10_000));
+        assert_eq!(download.downloaded(), 4_000);
+        let path = download_firmware(
+            &reqwest::Client::new(),
+            download,
+            &Throttle::default(),
+            0,
+            no_delay(),
+            |_| {},
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(std::fs::read(path).unwrap(), body(10_000));
+        assert!(requests.lock().unwrap()[0].contains("range: bytes=4000-"));
+
+        // A complete file, or one without a validator, starts over
+        let complete = Download::new(&url, dir.path()).resume(Some("\"v1\"".to_string()), Some(10_000));
+        assert_eq!(complete.downloaded(), 0);
+        assert_eq!(Download::new(&url, dir.path()).resume(None, Some(20_000)).downloaded(), 0);
+    }
 }
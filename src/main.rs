@@ -1,76 +1,206 @@
 mod auth;
+mod backoff;
 mod channel;
 mod client;
 mod config;
+mod control;
+mod dns;
+mod failover;
 mod firmware;
+mod net;
+mod network;
+mod provision;
 mod serial;
+mod state;
+mod status;
+mod update;
+mod validation;
 
+use backoff::Backoff;
 use client::{ClientEvent, NervesHubClient};
 use config::Config;
+use failover::HostSelector;
+use network::NetworkMonitor;
 use std::path::PathBuf;
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
 use tokio::sync::mpsc;
 use tracing::{error, info, warn};
 
-fn backoff_delay(attempt: u32) -> std::time::Duration {
-    let base_secs: f64 = (2.0_f64).powi(attempt as i32).min(60.0);
-    let jitter = rand::random::<f64>() * base_secs * 0.5;
-    std::time::Duration::from_secs_f64(base_secs + jitter)
+/// How long shutdown waits for a cancelled update to report back.
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+
+/// How long the connection gets to send that report.
+const REPORT_FLUSH: Duration = Duration::from_secs(1);
+
+/// Resolve on SIGTERM or SIGINT.
+async fn shutdown_signal() {
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(e) => {
+            warn!(error = %e, "cannot listen for SIGTERM");
+            let _ = tokio::signal::ctrl_c().await;
+            return;
+        }
+    };
+    tokio::select! {
+        _ = terminate.recv() => info!("received SIGTERM"),
+        _ = tokio::signal::ctrl_c() => info!("received SIGINT"),
+    }
 }
 
 async fn run_daemon(config: Config) -> Result<(), Box<dyn std::error::Error>> {
+    let control_socket = config.control_socket.clone();
+    let mut hosts = HostSelector::new(config.hosts.clone(), config.failover());
+    let mut network = NetworkMonitor::new(
+        config.connectivity_check_command.clone(),
+        std::time::Duration::from_secs(config.connectivity_check_interval_secs()),
+    );
+    let exit_on_auth_failure = config.reconnect().exit_on_auth_failure();
+    let mut backoff = Backoff::new(&config.reconnect());
     let client = NervesHubClient::new(config)?;
-    let mut attempt: u32 = 0;
-
-    loop {
-        let (event_tx, mut event_rx) = mpsc::channel::<ClientEvent>(32);
-
-        // Spawn event handler
-        let event_handle = tokio::spawn(async move {
-            while let Some(event) = event_rx.recv().await {
-                match event {
-                    ClientEvent::Connected => info!("connected to server"),
-                    ClientEvent::Joined => info!("joined device channel"),
-                    ClientEvent::UpdateAvailable(info) => {
-                        info!(
-                            uuid = %info.firmware_meta.uuid,
-                            version = %info.firmware_meta.version,
-                            "firmware update available"
-                        );
-                    }
-                    ClientEvent::FirmwareDownloaded(path) => {
-                        info!(path = %path.display(), "firmware downloaded");
-                    }
-                    ClientEvent::FirmwareApplied => {
-                        info!("firmware applied successfully");
-                    }
-                    ClientEvent::RebootRequested => {
-                        info!("reboot requested by server");
-                        // In a real deployment, trigger system reboot here
+    if let Some(watchdog) = client.validation_watchdog() {
+        info!("new firmware is not validated yet, watchdog started");
+        tokio::spawn(watchdog);
+    }
+
+    if let Some(path) = control_socket {
+        let status = client.status();
+        let downloads = client.downloads();
+        tokio::spawn(async move {
+            if let Err(e) = control::serve(&path, status, downloads).await {
+                error!(path = %path.display(), error = %e, "control socket failed");
+            }
+        });
+    }
+
+    let downloads = client.downloads();
+    let daemon = async {
+        loop {
+            let (event_tx, mut event_rx) = mpsc::channel::<ClientEvent>(32);
+
+            // Spawn event handler
+            let event_handle = tokio::spawn(async move {
+                while let Some(event) = event_rx.recv().await {
+                    match event {
+                        ClientEvent::Connected(host) => info!(host = %host, "connected to server"),
+                        ClientEvent::Joined => info!("joined device channel"),
+                        ClientEvent::UpdateAvailable(info) => {
+                            info!(
+                                uuid = %info.firmware_meta.uuid,
+                                version = %info.firmware_meta.version,
+                                "firmware update available"
+                            );
+                        }
+                        ClientEvent::FirmwareDownloaded(path) => {
+                            info!(path = %path.display(), "firmware downloaded");
+                        }
+                        ClientEvent::FirmwareApplied => {
+                            info!("firmware applied successfully");
+                        }
+                        ClientEvent::UpdateCancelled(reason) => {
+                            warn!(reason = %reason, "firmware update cancelled");
+                        }
+                        ClientEvent::RebootRequested => {
+                            info!("reboot requested by server");
+                            // In a real deployment, trigger system reboot here
+                        }
+                        ClientEvent::Disconnected(reason) => {
+                            warn!(reason = %reason, "disconnected");
+                        }
+                        ClientEvent::CertificateExpiring {
+                            serial,
+                            days_remaining,
+                        } if days_remaining < 0 => {
+                            error!(serial = %serial, days_remaining, "device certificate has expired");
+                        }
+                        ClientEvent::CertificateExpiring {
+                            serial,
+                            days_remaining,
+                        } => {
+                            warn!(serial = %serial, days_remaining, "device certificate expires soon");
+                        }
                     }
-                    ClientEvent::Disconnected(reason) => {
-                        warn!(reason = %reason, "disconnected");
+                }
+            });
+
+            let host = hosts.current().to_string();
+            let session = client.run(&host, event_tx).await;
+            event_handle.abort();
+            backoff.connection_ended(session.joined_for);
+
+            let mut retry_after = None;
+            let mut auth_failure = None;
+            match session.result {
+                Ok(()) => {
+                    info!(host = %host, "connection ended cleanly");
+                    hosts.record_success();
+                }
+                Err(e) => {
+                    error!(host = %host, error = %e, "connection error");
+                    retry_after = e.retry_after();
+                    auth_failure = e.is_auth_failure().then_some(e);
+                    // Fail over right away; back off once every host has failed
+                    if !hosts.record_failure() {
+                        info!(host = hosts.current(), "failing over to next host");
+                        continue;
                     }
                 }
             }
-        });
 
-        match client.run(event_tx).await {
-            Ok(()) => {
-                info!("connection ended cleanly");
-                attempt = 0;
-            }
-            Err(e) => {
-                error!(error = %e, "connection error");
+            let delay = match auth_failure {
+                Some(e) if exit_on_auth_failure => return Err(e.into()),
+                Some(_) => {
+                    let delay = backoff.auth_failure_delay();
+                    warn!(delay_secs = delay.as_secs_f64(), "server refused the device, waiting");
+                    delay
+                }
+                None => backoff.delay_with_hint(retry_after),
+            };
+            if !network.is_up().await {
+                // Not the server's fault: reconnect as soon as the network is
+                // back, without escalating the backoff
+                info!(max_wait_secs = delay.as_secs_f64(), "network is down, waiting for it");
+                tokio::select! {
+                    _ = network.wait_up() => info!("network is up, reconnecting"),
+                    _ = tokio::time::sleep(delay) => {}
+                }
+                continue;
             }
-        }
 
-        event_handle.abort();
+            info!(
+                delay_secs = delay.as_secs_f64(),
+                attempt = backoff.attempt(),
+                "reconnecting"
+            );
+            tokio::time::sleep(delay).await;
+            backoff.escalate();
+        }
+    };
+    tokio::pin!(daemon);
+    tokio::select! {
+        result = &mut daemon => return result,
+        _ = shutdown_signal() => {}
+    }
 
-        let delay = backoff_delay(attempt);
-        info!(delay_secs = delay.as_secs_f64(), attempt, "reconnecting");
-        tokio::time::sleep(delay).await;
-        attempt = attempt.saturating_add(1).min(6); // Cap at ~60s base
+    info!("shutting down");
+    if downloads.cancel("hub_link shutting down") {
+        // Give the update a moment to clean up and report the cancellation
+        let finished = tokio::time::timeout(SHUTDOWN_GRACE, async {
+            tokio::select! {
+                _ = &mut daemon => return,
+                _ = downloads.settled() => {}
+            }
+            // The update runs in the background; its report goes out over
+            // the connection
+            let _ = tokio::time::timeout(REPORT_FLUSH, &mut daemon).await;
+        })
+        .await;
+        if finished.is_err() {
+            warn!("firmware update did not stop in time");
+        }
     }
+    Ok(())
 }
 
 #[tokio::main]
@@ -86,8 +216,10 @@ async fn main() {
         )
         .init();
 
-    let config_path = std::env::args()
-        .nth(1)
+    let mut args = std::env::args().skip(1).peekable();
+    let provision_mode = args.next_if(|arg| arg == "provision").is_some();
+    let config_path = args
+        .next()
         .map(PathBuf::from)
         .unwrap_or_else(|| PathBuf::from("/etc/hub_link/config.toml"));
 
@@ -99,8 +231,16 @@ async fn main() {
         }
     };
 
+    if provision_mode {
+        if let Err(e) = provision::provision(&config).await {
+            error!(error = %e, "provisioning failed");
+            std::process::exit(1);
+        }
+        return;
+    }
+
     info!(
-        host = %config.host,
+        hosts = ?config.hosts,
         "starting hub_link daemon"
     );
 
@@ -109,28 +249,3 @@ async fn main() {
         std::process::exit(1);
     }
 }
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    #[test]
-    fn backoff_delay_increases() {
-        let d0 = backoff_delay(0);
-        let d1 = backoff_delay(1);
-        let d3 = backoff_delay(3);
-        // With jitter, we can't assert exact values, but the base increases
-        // d0 base=1s, d1 base=2s, d3 base=8s
-        // With up to 50% jitter, max is 1.5s, 3s, 12s
-        assert!(d0.as_secs_f64() <= 1.5);
-        assert!(d1.as_secs_f64() <= 3.0);
-        assert!(d3.as_secs_f64() <= 12.0);
-    }
-
-    #[test]
-    fn backoff_delay_caps() {
-        let d10 = backoff_delay(10);
-        // Base capped at 60s, with 50% jitter max is 90s
-        assert!(d10.as_secs_f64() <= 90.0);
-    }
-}
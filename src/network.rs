@@ -0,0 +1,265 @@
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use tokio::io::unix::AsyncFd;
+use tokio::time::Duration;
+use tracing::{debug, warn};
+
+const ROUTE_TABLE: &str = "/proc/net/route";
+const IPV6_ROUTE_TABLE: &str = "/proc/net/ipv6_route";
+
+const RTF_UP: u32 = 0x0001;
+const RTF_REJECT: u32 = 0x0200;
+
+/// Tells whether the device has network connectivity and wakes the reconnect
+/// loop as soon as it comes back.
+///
+/// Without a check command, "up" means the kernel has a default route.
+/// Route and address changes are watched over netlink; if that socket cannot
+/// be opened the state is polled instead.
+#[derive(Debug)]
+pub struct NetworkMonitor {
+    check_command: Option<String>,
+    poll_interval: Duration,
+    netlink: Option<AsyncFd<OwnedFd>>,
+}
+
+impl NetworkMonitor {
+    pub fn new(check_command: Option<String>, poll_interval: Duration) -> Self {
+        let netlink = match open_netlink() {
+            Ok(fd) => Some(fd),
+            Err(e) => {
+                warn!(error = %e, "cannot watch netlink route changes, polling instead");
+                None
+            }
+        };
+        Self {
+            check_command,
+            poll_interval,
+            netlink,
+        }
+    }
+
+    /// Whether the network is currently usable.
+    pub async fn is_up(&self) -> bool {
+        match &self.check_command {
+            Some(command) => run_check(command).await,
+            None => has_default_route(),
+        }
+    }
+
+    /// Wait until the network is usable.
+    pub async fn wait_up(&mut self) {
+        loop {
+            if self.is_up().await {
+                return;
+            }
+            tokio::select! {
+                _ = next_netlink_event(self.netlink.as_ref()) => {
+                    debug!("network configuration changed");
+                }
+                _ = tokio::time::sleep(self.poll_interval) => {}
+            }
+        }
+    }
+}
+
+/// Run the connectivity check command; exit status 0 means connected.
+async fn run_check(command: &str) -> bool {
+    match tokio::process::Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .status()
+        .await
+    {
+        Ok(status) => status.success(),
+        Err(e) => {
+            warn!(error = %e, "failed to run connectivity check");
+            false
+        }
+    }
+}
+
+fn has_default_route() -> bool {
+    let ipv4 = std::fs::read_to_string(ROUTE_TABLE).unwrap_or_default();
+    let ipv6 = std::fs::read_to_string(IPV6_ROUTE_TABLE).unwrap_or_default();
+    has_ipv4_default_route(&ipv4) || has_ipv6_default_route(&ipv6)
+}
+
+/// Look for an up default route in `/proc/net/route` contents.
+fn has_ipv4_default_route(table: &str) -> bool {
+    table.lines().skip(1).any(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        // Iface Destination Gateway Flags RefCnt Use Metric Mask ...
+        fields.len() >= 8
+            && fields[1] == "00000000"
+            && fields[7] == "00000000"
+            && route_usable(fields[3])
+    })
+}
+
+/// Look for an up default route in `/proc/net/ipv6_route` contents.
+fn has_ipv6_default_route(table: &str) -> bool {
+    table.lines().any(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        // dest dest_len src src_len next_hop metric refcnt use flags iface
+        fields.len() >= 10
+            && fields[0].bytes().all(|b| b == b'0')
+            && fields[1] == "00"
+            && fields[9] != "lo"
+            && route_usable(fields[8])
+    })
+}
+
+fn route_usable(flags: &str) -> bool {
+    u32::from_str_radix(flags, 16)
+        .map(|flags| flags & RTF_UP != 0 && flags & RTF_REJECT == 0)
+        .unwrap_or(false)
+}
+
+/// Open a netlink socket subscribed to link, address and route changes.
+fn open_netlink() -> io::Result<AsyncFd<OwnedFd>> {
+    // SAFETY: plain socket(2) call; the result is checked before use
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_NETLINK,
+            libc::SOCK_RAW | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+            libc::NETLINK_ROUTE,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: fd is a freshly created socket owned by nobody else
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    // SAFETY: sockaddr_nl is plain data; all-zero is a valid value
+    let mut addr: libc::sockaddr_nl = unsafe { std::mem::zeroed() };
+    addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+    addr.nl_groups = (libc::RTMGRP_LINK
+        | libc::RTMGRP_IPV4_IFADDR
+        | libc::RTMGRP_IPV6_IFADDR
+        | libc::RTMGRP_IPV4_ROUTE
+        | libc::RTMGRP_IPV6_ROUTE) as u32;
+    // SAFETY: addr is a valid sockaddr_nl and the length matches it
+    let rc = unsafe {
+        libc::bind(
+            fd.as_raw_fd(),
+            &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+            std::mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+        )
+    };
+    if rc < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    AsyncFd::new(fd)
+}
+
+/// Wait for netlink messages and drain them. Never completes without a socket.
+async fn next_netlink_event(netlink: Option<&AsyncFd<OwnedFd>>) {
+    let Some(netlink) = netlink else {
+        return std::future::pending().await;
+    };
+    let mut buf = [0u8; 8192];
+    loop {
+        let Ok(mut guard) = netlink.readable().await else {
+            return std::future::pending().await;
+        };
+        // The contents don't matter: any change triggers a fresh check
+        let read = guard.try_io(|fd| {
+            // SAFETY: buf is valid for writes of buf.len() bytes
+            let n = unsafe {
+                libc::recv(
+                    fd.as_raw_fd(),
+                    buf.as_mut_ptr() as *mut libc::c_void,
+                    buf.len(),
+                    0,
+                )
+            };
+            if n < 0 {
+                Err(io::Error::last_os_error())
+            } else {
+                Ok(n)
+            }
+        });
+        match read {
+            Ok(Ok(_)) => return,
+            // Buffer overrun (ENOBUFS) still means something changed
+            Ok(Err(e)) if e.raw_os_error() == Some(libc::ENOBUFS) => return,
+            Ok(Err(e)) => {
+                warn!(error = %e, "netlink read failed");
+                return std::future::pending().await;
+            }
+            Err(_would_block) => continue,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ipv4_default_route() {
+        let table = "\
+Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT
+eth0\t00000000\t010200C0\t0003\t0\t0\t0\t00000000\t0\t0\t0
+eth0\t000200C0\t00000000\t0001\t0\t0\t0\t00FFFFFF\t0\t0\t0
+";
+        assert!(has_ipv4_default_route(table));
+
+        let no_default = "\
+Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT
+eth0\t000200C0\t00000000\t0001\t0\t0\t0\t00FFFFFF\t0\t0\t0
+";
+        assert!(!has_ipv4_default_route(no_default));
+        assert!(!has_ipv4_default_route(""));
+    }
+
+    #[test]
+    fn ipv6_default_route() {
+        let zero = "00000000000000000000000000000000";
+        let table = format!(
+            "{zero} 00 {zero} 00 fd000000000000000000000000000001 00000400 00000001 00000000 00000003     eth0\n"
+        );
+        assert!(has_ipv6_default_route(&table));
+
+        // Unreachable default on loopback, as installed without connectivity
+        let reject = format!(
+            "{zero} 00 {zero} 00 {zero} ffffffff 00000001 00000000 00200200       lo\n"
+        );
+        assert!(!has_ipv6_default_route(&reject));
+    }
+
+    #[tokio::test]
+    async fn check_command_decides() {
+        let up = NetworkMonitor::new(Some("true".to_string()), Duration::from_millis(10));
+        assert!(up.is_up().await);
+        let down = NetworkMonitor::new(Some("false".to_string()), Duration::from_millis(10));
+        assert!(!down.is_up().await);
+    }
+
+    #[tokio::test]
+    async fn wait_up_polls_check() {
+        let dir = tempfile::tempdir().unwrap();
+        let flag = dir.path().join("up");
+        let mut monitor = NetworkMonitor::new(
+            Some(format!("test -e {}", flag.display())),
+            Duration::from_millis(10),
+        );
+
+        let waiter = tokio::spawn(async move {
+            monitor.wait_up().await;
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!waiter.is_finished());
+
+        std::fs::write(&flag, "").unwrap();
+        tokio::time::timeout(Duration::from_secs(5), waiter)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+}
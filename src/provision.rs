@@ -0,0 +1,314 @@
+use crate::config::{AuthConfig, Config, ProvisionConfig};
+use crate::net::{self, Dialer};
+use crate::serial;
+use rcgen::{CertificateParams, DistinguishedName, DnType, KeyPair};
+use std::path::Path;
+use thiserror::Error;
+use tracing::info;
+
+#[derive(Debug, Error)]
+pub enum ProvisionError {
+    #[error("provisioning is not configured (missing [provision] section)")]
+    NotConfigured,
+    #[error("provisioning requires mtls auth with cert_path and key_path")]
+    NotMtls,
+    #[error("device certificate already exists at {0}")]
+    AlreadyProvisioned(String),
+    #[error("serial number error: {0}")]
+    Serial(#[from] serial::SerialError),
+    #[error("failed to generate key or CSR: {0}")]
+    Csr(#[from] rcgen::Error),
+    #[error("network error: {0}")]
+    Network(#[from] net::NetError),
+    #[error("certificate request failed: {0}")]
+    Request(String),
+    #[error("invalid certificate from server: {0}")]
+    InvalidCert(String),
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+/// Generate a device key pair, have a CSR for it signed by the provisioning
+/// endpoint and store key and certificate at the mTLS `key_path`/`cert_path`.
+///
+/// Refuses to run if the certificate already exists.
+pub async fn provision(config: &Config) -> Result<(), ProvisionError> {
+    let provision = config
+        .provision
+        .as_ref()
+        .ok_or(ProvisionError::NotConfigured)?;
+    let (cert_path, key_path) = config
+        .auth
+        .iter()
+        .find_map(|auth| match auth {
+            AuthConfig::Mtls {
+                cert_path,
+                key_path: Some(key_path),
+                ..
+            } => Some((cert_path, key_path)),
+            _ => None,
+        })
+        .ok_or(ProvisionError::NotMtls)?;
+    if cert_path.exists() {
+        return Err(ProvisionError::AlreadyProvisioned(
+            cert_path.display().to_string(),
+        ));
+    }
+
+    let serial = serial::resolve_serial(
+        config.serial_number.as_deref(),
+        config.serial_number_command.as_deref(),
+    )?;
+    info!(serial = %serial, url = %provision.url, "requesting device certificate");
+
+    let (key, csr_pem) = generate_csr(&serial)?;
+    let client = Dialer::from_config(config)?.http_client()?;
+    let cert_pem = request_certificate(&client, provision, &csr_pem).await?;
+    check_certificate(&cert_pem, &key)?;
+
+    // Key first: a certificate without its key would block a retry
+    write_file(key_path, key.serialize_pem().as_bytes(), 0o600)?;
+    write_file(cert_path, cert_pem.as_bytes(), 0o644)?;
+    info!(
+        cert = %cert_path.display(),
+        key = %key_path.display(),
+        "device provisioned"
+    );
+    Ok(())
+}
+
+/// Generate an ECDSA P-256 key and a CSR with the serial number as CN.
+fn generate_csr(serial: &str) -> Result<(KeyPair, String), ProvisionError> {
+    let key = KeyPair::generate()?;
+    let mut params = CertificateParams::default();
+    let mut name = DistinguishedName::new();
+    name.push(DnType::CommonName, serial);
+    params.distinguished_name = name;
+    let csr = params.serialize_request(&key)?;
+    Ok((key, csr.pem()?))
+}
+
+/// POST the PEM CSR to the provisioning endpoint and return the PEM certificate.
+async fn request_certificate(
+    client: &reqwest::Client,
+    provision: &ProvisionConfig,
+    csr_pem: &str,
+) -> Result<String, ProvisionError> {
+    let mut request = client
+        .post(&provision.url)
+        .header(reqwest::header::CONTENT_TYPE, "application/pkcs10")
+        .body(csr_pem.to_string());
+    if let Some(token) = &provision.token {
+        request = request.bearer_auth(token);
+    }
+
+    let response = request
+        .send()
+        .await
+        .map_err(|e| ProvisionError::Request(e.to_string()))?;
+    let status = response.status();
+    let body = response
+        .text()
+        .await
+        .map_err(|e| ProvisionError::Request(e.to_string()))?;
+    if !status.is_success() {
+        return Err(ProvisionError::Request(format!(
+            "HTTP {}: {}",
+            status,
+            body.trim()
+        )));
+    }
+    Ok(body)
+}
+
+/// Check that the returned leaf certificate is for our key.
+fn check_certificate(cert_pem: &str, key: &KeyPair) -> Result<(), ProvisionError> {
+    let certs = rustls_pemfile::certs(&mut cert_pem.as_bytes())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| ProvisionError::InvalidCert(e.to_string()))?;
+    let leaf = certs
+        .first()
+        .ok_or_else(|| ProvisionError::InvalidCert("no certificate in response".to_string()))?;
+    let (_, cert) = x509_parser::parse_x509_certificate(leaf)
+        .map_err(|e| ProvisionError::InvalidCert(e.to_string()))?;
+    if cert.public_key().raw != key.public_key_der().as_slice() {
+        return Err(ProvisionError::InvalidCert(
+            "certificate does not match the generated key".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Write `contents` via a temporary file so a crash never leaves a partial file.
+fn write_file(path: &Path, contents: &[u8], mode: u32) -> Result<(), std::io::Error> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let tmp_path = path.with_extension("tmp");
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(mode)
+        .open(&tmp_path)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    std::fs::rename(&tmp_path, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Minimal provisioning endpoint: signs the CSR with a test CA, or
+    /// answers with `reject_status` when set.
+    async fn stub_server(reject_status: Option<u16>) -> (String, tokio::task::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/csr", listener.local_addr().unwrap());
+
+        let handle = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 4096];
+            let (head, body) = loop {
+                let n = stream.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..n]);
+                let text = String::from_utf8_lossy(&request).to_string();
+                if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                    let len: usize = head
+                        .lines()
+                        .find_map(|line| {
+                            let line = line.to_lowercase();
+                            let value = line.strip_prefix("content-length:")?;
+                            value.trim().parse().ok()
+                        })
+                        .unwrap_or(0);
+                    if body.len() >= len {
+                        break (head.to_string(), body.to_string());
+                    }
+                }
+            };
+
+            let (status, reply) = match reject_status {
+                Some(status) => (status, "denied".to_string()),
+                None => {
+                    let ca_key = KeyPair::generate().unwrap();
+                    let mut ca_params = CertificateParams::default();
+                    ca_params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+                    let ca = ca_params.self_signed(&ca_key).unwrap();
+                    let csr = rcgen::CertificateSigningRequestParams::from_pem(&body).unwrap();
+                    (200, csr.signed_by(&ca, &ca_key).unwrap().pem())
+                }
+            };
+            let response = format!(
+                "HTTP/1.1 {} OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                reply.len(),
+                reply
+            );
+            stream.write_all(response.as_bytes()).await.unwrap();
+            format!("{}\r\n\r\n{}", head, body)
+        });
+        (url, handle)
+    }
+
+    fn test_config(dir: &Path, url: &str) -> Config {
+        let toml = format!(
+            r#"
+host = "example.com"
+serial_number = "factory-0042"
+
+[auth]
+type = "mtls"
+cert_path = "{dir}/certs/device.pem"
+key_path = "{dir}/certs/device-key.pem"
+ca_cert_path = "{dir}/ca.pem"
+
+[provision]
+url = "{url}"
+token = "factory-token"
+
+[firmware]
+uuid = "u"
+version = "v"
+platform = "p"
+architecture = "a"
+product = "pr"
+"#,
+            dir = dir.display(),
+            url = url
+        );
+        Config::from_str(&toml).unwrap()
+    }
+
+    #[test]
+    fn csr_has_serial_as_cn() {
+        let (_key, csr_pem) = generate_csr("device-77").unwrap();
+        let params = rcgen::CertificateSigningRequestParams::from_pem(&csr_pem).unwrap();
+        let cn = params.params.distinguished_name.get(&DnType::CommonName);
+        assert_eq!(
+            cn,
+            Some(&rcgen::DnValue::Utf8String("device-77".to_string()))
+        );
+    }
+
+    #[test]
+    fn rejects_certificate_for_other_key() {
+        let (key, _csr) = generate_csr("device-77").unwrap();
+        let other_key = KeyPair::generate().unwrap();
+        let cert = CertificateParams::default().self_signed(&other_key).unwrap();
+        assert!(matches!(
+            check_certificate(&cert.pem(), &key),
+            Err(ProvisionError::InvalidCert(_))
+        ));
+        assert!(check_certificate("not a cert", &key).is_err());
+    }
+
+    #[tokio::test]
+    async fn provisions_device() {
+        let dir = tempfile::tempdir().unwrap();
+        let (url, server) = stub_server(None).await;
+        let config = test_config(dir.path(), &url);
+
+        provision(&config).await.unwrap();
+
+        let request = server.await.unwrap();
+        assert!(request.starts_with("POST /csr"));
+        assert!(request
+            .to_lowercase()
+            .contains("authorization: bearer factory-token"));
+        assert!(request.contains("BEGIN CERTIFICATE REQUEST"));
+
+        let cert_path = dir.path().join("certs/device.pem");
+        let key_path = dir.path().join("certs/device-key.pem");
+        let key = KeyPair::from_pem(&std::fs::read_to_string(&key_path).unwrap()).unwrap();
+        check_certificate(&std::fs::read_to_string(&cert_path).unwrap(), &key).unwrap();
+        let mode = std::fs::metadata(&key_path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        // A second run must not replace the provisioned identity
+        assert!(matches!(
+            provision(&config).await,
+            Err(ProvisionError::AlreadyProvisioned(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn server_rejection() {
+        let dir = tempfile::tempdir().unwrap();
+        let (url, _server) = stub_server(Some(403)).await;
+        let config = test_config(dir.path(), &url);
+
+        let err = provision(&config).await.unwrap_err();
+        assert!(matches!(err, ProvisionError::Request(_)));
+        assert!(err.to_string().contains("403"));
+        assert!(!dir.path().join("certs/device.pem").exists());
+        assert!(!dir.path().join("certs/device-key.pem").exists());
+    }
+}
@@ -0,0 +1,299 @@
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tracing::warn;
+
+const STATE_FILE: &str = "update_state.json";
+const BOOT_ID: &str = "/proc/sys/kernel/random/boot_id";
+
+/// Where an update is in its lifecycle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum UpdatePhase {
+    /// Downloading; a partial file in `data_dir` can be resumed.
+    Downloading,
+    /// fwup wrote the firmware; it runs after the next reboot.
+    Applied,
+    /// The server asked for the reboot into the new firmware.
+    AwaitingReboot,
+    /// The device booted the new firmware; it isn't validated yet.
+    Booted,
+    /// The new firmware reached the server and was marked good.
+    Validated,
+    /// The update did not take; the reason is in `error`.
+    Failed,
+}
+
+/// The update in progress, or the last one whose outcome isn't reported yet.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UpdateRecord {
+    pub uuid: String,
+    pub version: String,
+    pub phase: UpdatePhase,
+    /// Kernel boot id when the phase was entered.
+    pub boot_id: String,
+    /// ETag or Last-Modified of the partial download.
+    #[serde(default)]
+    pub validator: Option<String>,
+    /// Full size of the download, if known.
+    #[serde(default)]
+    pub total: Option<u64>,
+    /// The download is a delta file rather than the full image.
+    #[serde(default)]
+    pub delta: bool,
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+/// Firmware update state kept across restarts and reboots.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UpdateState {
+    pub update: Option<UpdateRecord>,
+}
+
+impl UpdateState {
+    /// Whether `uuid` was applied during this boot and only needs a reboot.
+    pub fn is_pending(&self, uuid: &str, boot_id: &str) -> bool {
+        self.update.as_ref().is_some_and(|update| {
+            update.uuid == uuid
+                && update.boot_id == boot_id
+                && matches!(
+                    update.phase,
+                    UpdatePhase::Applied | UpdatePhase::AwaitingReboot
+                )
+        })
+    }
+
+    /// Settle an update applied before a reboot against the firmware that
+    /// is running now. Returns whether anything changed.
+    pub fn settle(&mut self, running_uuid: &str, boot_id: &str) -> bool {
+        let Some(update) = &mut self.update else {
+            return false;
+        };
+        if update.boot_id == boot_id
+            || !matches!(
+                update.phase,
+                UpdatePhase::Applied | UpdatePhase::AwaitingReboot
+            )
+        {
+            return false;
+        }
+        if update.uuid == running_uuid {
+            update.phase = UpdatePhase::Booted;
+        } else {
+            update.phase = UpdatePhase::Failed;
+            update.error = Some(format!(
+                "device booted firmware {} instead of {}",
+                running_uuid, update.uuid
+            ));
+        }
+        update.boot_id = boot_id.to_string();
+        true
+    }
+}
+
+/// Reads and writes the update state in `data_dir`. Clones share a lock,
+/// so concurrent updates from the client and the updater don't get lost.
+#[derive(Debug, Clone)]
+pub struct StateStore {
+    path: PathBuf,
+    boot_id: String,
+    lock: Arc<Mutex<()>>,
+}
+
+impl StateStore {
+    pub fn new(data_dir: &Path) -> Self {
+        let boot_id = std::fs::read_to_string(BOOT_ID)
+            .map(|id| id.trim().to_string())
+            .unwrap_or_default();
+        Self {
+            path: data_dir.join(STATE_FILE),
+            boot_id,
+            lock: Arc::default(),
+        }
+    }
+
+    pub fn boot_id(&self) -> &str {
+        &self.boot_id
+    }
+
+    /// Load the saved state. A missing or unreadable file gives the default
+    /// state.
+    pub fn load(&self) -> UpdateState {
+        match std::fs::read(&self.path) {
+            Ok(data) => serde_json::from_slice(&data).unwrap_or_else(|e| {
+                warn!(path = %self.path.display(), error = %e, "ignoring corrupt update state");
+                UpdateState::default()
+            }),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => UpdateState::default(),
+            Err(e) => {
+                warn!(path = %self.path.display(), error = %e, "cannot read update state");
+                UpdateState::default()
+            }
+        }
+    }
+
+    /// Save the state, replacing the file atomically.
+    pub fn save(&self, state: &UpdateState) -> io::Result<()> {
+        let _guard = self.lock();
+        self.write(state)
+    }
+
+    fn write(&self, state: &UpdateState) -> io::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let tmp = self.path.with_extension("json.tmp");
+        std::fs::write(&tmp, serde_json::to_vec_pretty(state)?)?;
+        std::fs::rename(&tmp, &self.path)
+    }
+
+    /// Modify the saved state in place.
+    pub fn update<F>(&self, f: F) -> io::Result<()>
+    where
+        F: FnOnce(&mut UpdateState),
+    {
+        let _guard = self.lock();
+        let mut state = self.load();
+        f(&mut state);
+        self.write(&state)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, ()> {
+        // The guarded data is the file; a panic elsewhere doesn't corrupt it
+        self.lock.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Start tracking an update in `phase`, replacing any earlier one.
+    pub fn begin(&self, uuid: &str, version: &str, phase: UpdatePhase) -> io::Result<()> {
+        self.update(|state| {
+            state.update = Some(UpdateRecord {
+                uuid: uuid.to_string(),
+                version: version.to_string(),
+                phase,
+                boot_id: self.boot_id.clone(),
+                validator: None,
+                total: None,
+                delta: false,
+                error: None,
+            })
+        })
+    }
+
+    /// Move the tracked update to `phase`.
+    pub fn set_phase(&self, phase: UpdatePhase) -> io::Result<()> {
+        self.update(|state| {
+            if let Some(update) = &mut state.update {
+                update.phase = phase;
+                update.boot_id = self.boot_id.clone();
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store(dir: &Path, boot_id: &str) -> StateStore {
+        StateStore {
+            path: dir.join(STATE_FILE),
+            boot_id: boot_id.to_string(),
+            lock: Arc::default(),
+        }
+    }
+
+    #[test]
+    fn pending_survives_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(store(dir.path(), "boot-1").load(), UpdateState::default());
+
+        store(dir.path(), "boot-1")
+            .begin("fw-2", "2.0.0", UpdatePhase::Applied)
+            .unwrap();
+        let state = store(dir.path(), "boot-1").load();
+        assert!(state.is_pending("fw-2", "boot-1"));
+        assert_eq!(state.update.unwrap().version, "2.0.0");
+    }
+
+    #[test]
+    fn pending_from_other_boot_is_not_pending() {
+        let dir = tempfile::tempdir().unwrap();
+        store(dir.path(), "boot-1")
+            .begin("fw-2", "2.0.0", UpdatePhase::Applied)
+            .unwrap();
+        assert!(!store(dir.path(), "boot-2").load().is_pending("fw-2", "boot-2"));
+    }
+
+    #[test]
+    fn settles_after_reboot() {
+        let dir = tempfile::tempdir().unwrap();
+        let before = store(dir.path(), "boot-1");
+        before.begin("fw-2", "2.0.0", UpdatePhase::Applied).unwrap();
+        before.set_phase(UpdatePhase::AwaitingReboot).unwrap();
+
+        // Same boot: nothing to settle
+        let mut state = before.load();
+        assert!(!state.settle("fw-1", "boot-1"));
+
+        let mut booted = state.clone();
+        assert!(booted.settle("fw-2", "boot-2"));
+        assert_eq!(booted.update.unwrap().phase, UpdatePhase::Booted);
+
+        assert!(state.settle("fw-1", "boot-2"));
+        let update = state.update.unwrap();
+        assert_eq!(update.phase, UpdatePhase::Failed);
+        assert_eq!(
+            update.error.as_deref(),
+            Some("device booted firmware fw-1 instead of fw-2")
+        );
+    }
+
+    #[test]
+    fn downloads_are_not_settled() {
+        let dir = tempfile::tempdir().unwrap();
+        store(dir.path(), "boot-1")
+            .begin("fw-2", "2.0.0", UpdatePhase::Downloading)
+            .unwrap();
+        let mut state = store(dir.path(), "boot-2").load();
+        assert!(!state.settle("fw-1", "boot-2"));
+        assert_eq!(state.update.unwrap().phase, UpdatePhase::Downloading);
+    }
+
+    #[test]
+    fn corrupt_state_is_ignored() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join(STATE_FILE), "{not json").unwrap();
+        assert_eq!(store(dir.path(), "boot-1").load(), UpdateState::default());
+    }
+
+    #[test]
+    fn concurrent_updates_are_not_lost() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = store(dir.path(), "boot-1");
+        store
+            .begin("fw-2", "2.0.0", UpdatePhase::Downloading)
+            .unwrap();
+
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let store = store.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..20 {
+                        store
+                            .update(|state| {
+                                let update = state.update.as_mut().unwrap();
+                                update.total = Some(update.total.unwrap_or(0) + 1);
+                            })
+                            .unwrap();
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(store.load().update.unwrap().total, Some(160));
+    }
+}
@@ -0,0 +1,70 @@
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
+
+/// Snapshot of the daemon state reported over the control socket.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Status {
+    pub connected: bool,
+    pub joined: bool,
+    /// Server host of the current connection.
+    pub host: Option<String>,
+    /// Auth method used for the current connection.
+    pub auth_method: Option<String>,
+    pub cert_serial: Option<String>,
+    pub cert_not_after: Option<i64>,
+    pub cert_days_remaining: Option<i64>,
+}
+
+/// Status shared between the client and the control socket.
+#[derive(Debug, Clone, Default)]
+pub struct SharedStatus(Arc<Mutex<Status>>);
+
+impl SharedStatus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Modify the status in place.
+    pub fn update<F>(&self, f: F)
+    where
+        F: FnOnce(&mut Status),
+    {
+        let mut status = self.0.lock().unwrap();
+        f(&mut status);
+    }
+
+    /// Return a copy of the current status.
+    pub fn snapshot(&self) -> Status {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn update_and_snapshot() {
+        let status = SharedStatus::new();
+        let other = status.clone();
+        other.update(|s| {
+            s.connected = true;
+            s.cert_days_remaining = Some(12);
+        });
+        let snap = status.snapshot();
+        assert!(snap.connected);
+        assert!(!snap.joined);
+        assert_eq!(snap.cert_days_remaining, Some(12));
+    }
+
+    #[test]
+    fn serializes_to_json() {
+        let status = Status {
+            connected: true,
+            ..Default::default()
+        };
+        let value = serde_json::to_value(&status).unwrap();
+        assert_eq!(value["connected"], true);
+        assert!(value["cert_days_remaining"].is_null());
+    }
+}
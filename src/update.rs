@@ -0,0 +1,630 @@
+use crate::backoff::Backoff;
+use crate::client::ClientEvent;
+use crate::config::{Config, DownloadConfig};
+use crate::firmware::{self, Download, DownloadControl, FirmwareError, Throttle, UpdateInfo};
+use crate::state::{StateStore, UpdatePhase};
+use serde_json::{json, Value};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+use tracing::{debug, error, info, warn};
+
+/// Reports a background update sends up to buffer while disconnected.
+const REPORT_BUFFER: usize = 16;
+
+/// What a background update tells the server, through the connection.
+#[derive(Debug, Clone, PartialEq)]
+pub enum UpdateReport {
+    /// Download progress in percent.
+    Progress(u8),
+    /// A `status_update` payload.
+    Status(Value),
+}
+
+/// Why a download didn't produce a file to apply.
+#[derive(Debug)]
+enum Stopped {
+    Cancelled(String),
+    Failed(FirmwareError),
+}
+
+/// The update task currently running.
+#[derive(Debug)]
+struct Running {
+    uuid: String,
+    handle: JoinHandle<()>,
+}
+
+/// Downloads and applies firmware updates in a background task, so the
+/// connection keeps sending heartbeats and reading messages meanwhile.
+///
+/// One update runs at a time; the task outlives a dropped connection and
+/// its reports are sent once the next one is up.
+#[derive(Debug, Clone)]
+pub struct Updater {
+    http: reqwest::Client,
+    state: StateStore,
+    downloads: DownloadControl,
+    data_dir: PathBuf,
+    /// fwup program; a stub in tests.
+    fwup: String,
+    devpath: String,
+    task: String,
+    download: DownloadConfig,
+    /// UUID of the running firmware, the source for delta updates.
+    running_uuid: String,
+    delta: bool,
+    reports: mpsc::Sender<UpdateReport>,
+    running: Arc<Mutex<Option<Running>>>,
+}
+
+impl Updater {
+    pub fn new(
+        config: &Config,
+        http: reqwest::Client,
+        state: StateStore,
+        downloads: DownloadControl,
+    ) -> (Self, mpsc::Receiver<UpdateReport>) {
+        let (reports, reports_rx) = mpsc::channel(REPORT_BUFFER);
+        let updater = Self {
+            http,
+            state,
+            downloads,
+            data_dir: config.data_dir(),
+            fwup: firmware::FWUP.to_string(),
+            devpath: config.fwup_devpath().to_string(),
+            task: config.fwup_task().to_string(),
+            download: config.download(),
+            running_uuid: config.firmware.uuid.clone(),
+            delta: config.update().delta(),
+            reports,
+            running: Arc::new(Mutex::new(None)),
+        };
+        (updater, reports_rx)
+    }
+
+    /// Start `update` in the background. A different update that is still
+    /// running is cancelled first, or waited for once fwup is writing it.
+    pub fn start(&self, update: UpdateInfo, events: mpsc::Sender<ClientEvent>) {
+        let uuid = update.firmware_meta.uuid.clone();
+        let mut running = self.running.lock().unwrap();
+        let mut superseded = None;
+        if let Some(current) = running.as_ref().filter(|r| !r.handle.is_finished()) {
+            if current.uuid == uuid {
+                debug!(uuid = %uuid, "update already in progress");
+                return;
+            }
+            let reason = format!("superseded by update {}", uuid);
+            if self.downloads.supersede(&reason) {
+                info!(uuid = %uuid, "new firmware update, cancelling the running one");
+            }
+            superseded = Some(reason);
+        }
+        let previous = running.take().map(|r| r.handle);
+        // Cancellable from here on, not only once the task gets to run
+        let cancelled = match superseded {
+            Some(_) => self.downloads.pending_cancellation(),
+            None => self.downloads.cancellation(),
+        };
+        let updater = self.clone();
+        let handle = tokio::spawn(async move {
+            if let Some(previous) = previous {
+                let _ = previous.await;
+            }
+            if let Some(reason) = superseded {
+                updater.downloads.withdraw(&reason);
+            }
+            updater.run(update, cancelled, events).await;
+        });
+        *running = Some(Running { uuid, handle });
+    }
+
+    /// Cancellable through `cancelled` until the download is done; fwup
+    /// isn't interrupted.
+    async fn run(
+        &self,
+        update: UpdateInfo,
+        mut cancelled: watch::Receiver<Option<String>>,
+        events: mpsc::Sender<ClientEvent>,
+    ) {
+        let meta = &update.firmware_meta;
+
+        if let Err(e) = tokio::fs::create_dir_all(&self.data_dir).await {
+            self.fail(&FirmwareError::Io(e)).await;
+            return;
+        }
+        if let Err(e) = firmware::preflight(&self.fwup, &self.devpath).await {
+            error!(error = %e, "firmware update rejected");
+            self.fail(&e).await;
+            return;
+        }
+
+        // Continue a download an earlier run left behind, if it's this update
+        let mut saved = self.state.load().update.filter(|saved| {
+            saved.uuid == meta.uuid && saved.phase == UpdatePhase::Downloading
+        });
+        let delta = update
+            .delta_for(&self.running_uuid)
+            .filter(|_| self.delta)
+            // A partial full image isn't given up for a delta
+            .filter(|_| saved.as_ref().is_none_or(|saved| saved.delta));
+        match &saved {
+            None => {
+                if let Err(e) = self.state.begin(&meta.uuid, &meta.version, UpdatePhase::Downloading) {
+                    warn!(error = %e, "failed to save update state");
+                }
+                self.restart_download(delta.is_some());
+            }
+            // The delta is no longer offered or wanted: start the full image
+            Some(partial) if partial.delta && delta.is_none() => {
+                info!(uuid = %meta.uuid, "partial delta download not used, downloading the full image");
+                remove_partial(&self.data_dir.join(firmware::DELTA_FILE)).await;
+                self.restart_download(false);
+                saved = None;
+            }
+            Some(_) => {}
+        }
+
+        if let Some(delta) = delta {
+            info!(uuid = %meta.uuid, source = %delta.source_uuid, "downloading delta firmware");
+            let download = Download::delta(&delta.firmware_url, &self.data_dir);
+            let delta_path = download.path().to_path_buf();
+            let download = match saved.take() {
+                Some(saved) => download.resume(saved.validator, saved.total),
+                None => download,
+            };
+            // fwup reads the rest of the image from the running firmware
+            match self.fetch(download, &mut cancelled, &events).await {
+                Ok(path) => {
+                    drop(cancelled);
+                    match firmware::apply_firmware(&self.fwup, &path, &self.devpath, &self.task).await {
+                        Ok(()) => {
+                            remove_partial(&path).await;
+                            self.applied(&events).await;
+                            return;
+                        }
+                        Err(e) => {
+                            warn!(error = %e, "delta firmware failed, downloading the full image")
+                        }
+                    }
+                    // The full image download is cancellable again, and
+                    // stops for a request sent while fwup ran
+                    cancelled = self.downloads.pending_cancellation();
+                }
+                Err(Stopped::Cancelled(reason)) => {
+                    self.cancelled(reason, &events).await;
+                    return;
+                }
+                Err(Stopped::Failed(e)) => {
+                    warn!(error = %e, "delta download failed, downloading the full image")
+                }
+            }
+            remove_partial(&delta_path).await;
+            self.restart_download(false);
+        }
+
+        info!(uuid = %meta.uuid, version = %meta.version, "downloading firmware");
+        let download = Download::new(&update.firmware_url, &self.data_dir);
+        let download = match saved {
+            Some(saved) => download.resume(saved.validator, saved.total),
+            None => download,
+        };
+        let firmware_path = match self.fetch(download, &mut cancelled, &events).await {
+            Ok(path) => path,
+            Err(Stopped::Cancelled(reason)) => {
+                self.cancelled(reason, &events).await;
+                return;
+            }
+            Err(Stopped::Failed(e)) => {
+                error!(error = %e, "firmware download failed");
+                self.fail(&e).await;
+                return;
+            }
+        };
+        drop(cancelled);
+
+        // Apply firmware
+        if let Err(e) = firmware::apply_firmware(&self.fwup, &firmware_path, &self.devpath, &self.task).await {
+            error!(error = %e, "firmware apply failed");
+            self.clear_state();
+            self.fail(&e).await;
+            return;
+        }
+        self.applied(&events).await;
+    }
+
+    /// Download a file, forwarding progress, until it is complete or the
+    /// update is cancelled. A cancelled download's partial file is removed.
+    async fn fetch(
+        &self,
+        download: Download,
+        cancelled: &mut watch::Receiver<Option<String>>,
+        events: &mpsc::Sender<ClientEvent>,
+    ) -> Result<PathBuf, Stopped> {
+        // Cancelled before the download started, e.g. while fwup was busy
+        if let Some(reason) = cancelled.borrow_and_update().clone() {
+            return Err(Stopped::Cancelled(reason));
+        }
+        let partial = download.path().to_path_buf();
+        let mut last_reported_percent: u8 = 0;
+        let (progress_tx, mut progress_rx) = mpsc::channel::<u8>(16);
+        let http = self.http.clone();
+        let store = self.state.clone();
+        let throttle = Throttle::new(&self.download, self.downloads.clone());
+        let retries = self.download.retries();
+        let backoff = Backoff::new(&self.download.retry_backoff());
+
+        let download_handle = tokio::spawn(async move {
+            let mut checkpoint = download.validator().map(str::to_string);
+            firmware::download_firmware(
+                &http,
+                download,
+                &throttle,
+                retries,
+                backoff,
+                |download| {
+                    let pct = firmware::progress_percent(download.downloaded(), download.total());
+                    let _ = progress_tx.try_send(pct);
+
+                    // Save what's needed to resume once a response starts
+                    if download.validator() != checkpoint.as_deref() {
+                        checkpoint = download.validator().map(str::to_string);
+                        let total = download.total();
+                        let saved = store.update(|state| {
+                            if let Some(update) = &mut state.update {
+                                update.validator = checkpoint.clone();
+                                update.total = total;
+                            }
+                        });
+                        if let Err(e) = saved {
+                            warn!(error = %e, "failed to save update state");
+                        }
+                    }
+                },
+            )
+            .await
+        });
+
+        // Forward progress while download is running
+        let cancel_reason = loop {
+            tokio::select! {
+                pct = progress_rx.recv() => {
+                    match pct {
+                        Some(pct) if pct > last_reported_percent + 4 || pct == 100 => {
+                            last_reported_percent = pct;
+                            // Dropped rather than stalling the download while disconnected
+                            let _ = self.reports.try_send(UpdateReport::Progress(pct));
+                        }
+                        Some(_) => {} // Skip small increments
+                        None => break None, // Channel closed, download done
+                    }
+                }
+                reason = cancelled.wait_for(Option::is_some) => {
+                    break reason.ok().and_then(|reason| (*reason).clone());
+                }
+            }
+        };
+
+        if let Some(reason) = cancel_reason {
+            download_handle.abort();
+            let _ = download_handle.await;
+            // A cancelled download isn't resumed
+            remove_partial(&partial).await;
+            return Err(Stopped::Cancelled(reason));
+        }
+
+        let path = match download_handle.await {
+            Ok(result) => result.map_err(Stopped::Failed)?,
+            Err(e) => return Err(Stopped::Failed(FirmwareError::Download(e.to_string()))),
+        };
+        info!(path = %path.display(), "firmware downloaded");
+        let _ = events.send(ClientEvent::FirmwareDownloaded(path.clone())).await;
+        Ok(path)
+    }
+
+    async fn applied(&self, events: &mpsc::Sender<ClientEvent>) {
+        if let Err(e) = self.state.set_phase(UpdatePhase::Applied) {
+            warn!(error = %e, "failed to save update state");
+        }
+        let _ = events.send(ClientEvent::FirmwareApplied).await;
+        self.report(json!({"status": "update-handled"})).await;
+    }
+
+    async fn cancelled(&self, reason: String, events: &mpsc::Sender<ClientEvent>) {
+        self.clear_state();
+        warn!(reason = %reason, "firmware update cancelled");
+        self.report(json!({"status": "update-cancelled", "reason": reason}))
+            .await;
+        let _ = events.send(ClientEvent::UpdateCancelled(reason)).await;
+    }
+
+    /// Queue a `status_update` for the server.
+    async fn report(&self, payload: Value) {
+        let _ = self.reports.send(UpdateReport::Status(payload)).await;
+    }
+
+    /// Tell the server the update failed and why.
+    async fn fail(&self, error: &FirmwareError) {
+        self.report(json!({"status": "update-failed", "reason": error.to_string()}))
+            .await;
+    }
+
+    fn clear_state(&self) {
+        if let Err(e) = self.state.update(|state| state.update = None) {
+            warn!(error = %e, "failed to save update state");
+        }
+    }
+
+    /// Record that the download starts over, as a delta or full image.
+    fn restart_download(&self, delta: bool) {
+        let saved = self.state.update(|state| {
+            if let Some(update) = &mut state.update {
+                update.delta = delta;
+                update.validator = None;
+                update.total = None;
+            }
+        });
+        if let Err(e) = saved {
+            warn!(error = %e, "failed to save update state");
+        }
+    }
+}
+
+/// Remove a downloaded file that won't be used.
+async fn remove_partial(path: &Path) {
+    if let Err(e) = tokio::fs::remove_file(path).await {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            warn!(path = %path.display(), error = %e, "failed to remove firmware file");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn update(uuid: &str) -> UpdateInfo {
+        update_at(uuid, "http://127.0.0.1:1")
+    }
+
+    fn update_at(uuid: &str, base_url: &str) -> UpdateInfo {
+        UpdateInfo::from_payload(&payload(uuid, base_url)).unwrap()
+    }
+
+    /// Update that also offers a delta against the running firmware.
+    fn delta_update_at(uuid: &str, base_url: &str) -> UpdateInfo {
+        let mut payload = payload(uuid, base_url);
+        payload["delta"] = json!({
+            "firmware_url": format!("{}/delta.fw", base_url),
+            "source_uuid": "fw-1"
+        });
+        UpdateInfo::from_payload(&payload).unwrap()
+    }
+
+    fn payload(uuid: &str, base_url: &str) -> Value {
+        json!({
+            "firmware_url": format!("{}/full.fw", base_url),
+            "firmware_meta": {
+                "uuid": uuid,
+                "version": "2.0.0",
+                "platform": "rpi4",
+                "architecture": "arm",
+                "product": "my-product"
+            }
+        })
+    }
+
+    fn updater(dir: &std::path::Path) -> (Updater, mpsc::Receiver<UpdateReport>) {
+        let config = Config::from_str(&format!(
+            r#"
+host = "localhost"
+serial_number = "device-1234"
+data_dir = "{dir}"
+fwup_devpath = "{dir}/missing-device"
+
+[auth]
+type = "mtls"
+cert_path = "/etc/hub_link/cert.pem"
+key_path = "/etc/hub_link/key.pem"
+ca_cert_path = "/etc/hub_link/ca.pem"
+
+[firmware]
+uuid = "fw-1"
+version = "1.0.0"
+platform = "rpi4"
+architecture = "arm"
+product = "my-product"
+"#,
+            dir = dir.display()
+        ))
+        .unwrap();
+        Updater::new(
+            &config,
+            reqwest::Client::new(),
+            StateStore::new(dir),
+            DownloadControl::new(),
+        )
+    }
+
+    /// Regular-file device and an fwup stub so preflight passes; `apply` is
+    /// the shell run for `fwup -a`.
+    fn stub_fwup(updater: &mut Updater, dir: &Path, apply: &str) {
+        let device = dir.join("device.img");
+        std::fs::write(&device, "").unwrap();
+        let fwup = dir.join("fwup");
+        std::fs::write(
+            &fwup,
+            format!(
+                "#!/bin/sh\nif [ \"$1\" = --version ]; then echo 1.10.0; exit 0; fi\n{}\n",
+                apply
+            ),
+        )
+        .unwrap();
+        std::fs::set_permissions(&fwup, std::fs::Permissions::from_mode(0o755)).unwrap();
+        updater.devpath = device.display().to_string();
+        updater.fwup = fwup.display().to_string();
+    }
+
+    /// HTTP server answering every request with `body`. Returns its base
+    /// URL and the request lines it got.
+    async fn serve(body: &'static [u8]) -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let seen = requests.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut head = Vec::new();
+                let mut byte = [0u8; 1];
+                while !head.ends_with(b"\r\n\r\n") {
+                    if stream.read_exact(&mut byte).await.is_err() {
+                        break;
+                    }
+                    head.push(byte[0]);
+                }
+                let head = String::from_utf8_lossy(&head).to_string();
+                seen.lock().unwrap().push(head.lines().next().unwrap_or("").to_string());
+                let reply = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nETag: \"v1\"\r\nConnection: close\r\n\r\n",
+                    body.len()
+                );
+                let _ = stream.write_all(reply.as_bytes()).await;
+                let _ = stream.write_all(body).await;
+            }
+        });
+        (url, requests)
+    }
+
+    async fn next_status(reports: &mut mpsc::Receiver<UpdateReport>) -> Value {
+        loop {
+            let report = tokio::time::timeout(Duration::from_secs(5), reports.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            if let UpdateReport::Status(payload) = report {
+                return payload;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn failure_is_reported_in_background() {
+        let dir = tempfile::tempdir().unwrap();
+        let (updater, mut reports) = updater(dir.path());
+        let (events, _events_rx) = mpsc::channel(8);
+
+        updater.start(update("fw-2"), events);
+        let status = next_status(&mut reports).await;
+        assert_eq!(status["status"], "update-failed");
+        assert!(status["reason"].as_str().unwrap().contains("missing-device"));
+    }
+
+    #[tokio::test]
+    async fn updates_run_one_at_a_time() {
+        let dir = tempfile::tempdir().unwrap();
+        let (updater, mut reports) = updater(dir.path());
+        let (events, _events_rx) = mpsc::channel(8);
+
+        updater.start(update("fw-2"), events.clone());
+        // Same update again while it runs: ignored
+        updater.start(update("fw-2"), events.clone());
+        updater.start(update("fw-3"), events);
+
+        // Each distinct update ran, in order, and reported once
+        assert_eq!(next_status(&mut reports).await["status"], "update-failed");
+        assert_eq!(next_status(&mut reports).await["status"], "update-failed");
+        let running = updater.running.lock().unwrap().take().unwrap();
+        assert_eq!(running.uuid, "fw-3");
+        tokio::time::timeout(Duration::from_secs(5), running.handle)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(reports.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn cancellable_once_started() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut updater, mut reports) = updater(dir.path());
+        stub_fwup(&mut updater, dir.path(), "exit 0");
+        let (url, requests) = serve(b"firmware").await;
+        let (events, _events_rx) = mpsc::channel(8);
+
+        // Before the task had a chance to run
+        updater.start(update_at("fw-2", &url), events);
+        assert!(updater.downloads.cancel("operator"));
+        let status = next_status(&mut reports).await;
+        assert_eq!(status["status"], "update-cancelled");
+        assert_eq!(status["reason"], "operator");
+        assert!(requests.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn cancel_during_delta_apply_skips_full_image() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut updater, mut reports) = updater(dir.path());
+        // The delta takes a while and then fails
+        stub_fwup(&mut updater, dir.path(), "sleep 0.3; exit 1");
+        let (url, requests) = serve(b"firmware").await;
+        let (events, mut events_rx) = mpsc::channel(8);
+
+        updater.start(delta_update_at("fw-2", &url), events.clone());
+        loop {
+            let event = tokio::time::timeout(Duration::from_secs(5), events_rx.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            if matches!(event, ClientEvent::FirmwareDownloaded(_)) {
+                break;
+            }
+        }
+        // fwup is applying the delta
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        updater.start(update("fw-3"), events);
+
+        let status = next_status(&mut reports).await;
+        assert_eq!(status["status"], "update-cancelled");
+        assert_eq!(status["reason"], "superseded by update fw-3");
+        let requests = requests.lock().unwrap();
+        assert_eq!(*requests, vec!["GET /delta.fw HTTP/1.1"]);
+    }
+
+    #[tokio::test]
+    async fn partial_delta_not_offered_restarts_full_image() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut updater, mut reports) = updater(dir.path());
+        stub_fwup(&mut updater, dir.path(), "exit 0");
+        let (url, requests) = serve(b"firmware").await;
+        let (events, _events_rx) = mpsc::channel(8);
+
+        // An earlier run left a partial delta; the server now offers none
+        let delta_path = dir.path().join(firmware::DELTA_FILE);
+        std::fs::write(&delta_path, "part").unwrap();
+        updater
+            .state
+            .begin("fw-2", "2.0.0", UpdatePhase::Downloading)
+            .unwrap();
+        updater
+            .state
+            .update(|state| {
+                let update = state.update.as_mut().unwrap();
+                update.delta = true;
+                update.validator = Some("\"d1\"".to_string());
+                update.total = Some(100);
+            })
+            .unwrap();
+
+        updater.start(update_at("fw-2", &url), events);
+        assert_eq!(next_status(&mut reports).await["status"], "update-handled");
+        assert!(!delta_path.exists());
+        assert_eq!(*requests.lock().unwrap(), vec!["GET /full.fw HTTP/1.1"]);
+        let saved = updater.state.load().update.unwrap();
+        assert!(!saved.delta);
+        assert_eq!(saved.validator.as_deref(), Some("\"v1\""));
+        assert_eq!(saved.phase, UpdatePhase::Applied);
+    }
+}
@@ -0,0 +1,166 @@
+use crate::config::UpdateConfig;
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+#[derive(Debug, Error)]
+pub enum ValidationError {
+    #[error("validation failed: {0}")]
+    Validate(String),
+    #[error("revert failed: {0}")]
+    Revert(String),
+}
+
+/// Marks new firmware as good once it booted and reached the server, and
+/// reverts it if that doesn't happen in time.
+#[derive(Debug, Clone)]
+pub struct FirmwareValidator {
+    /// Commands that mark the running firmware as validated.
+    commands: Vec<String>,
+    revert_command: String,
+    timeout: Option<Duration>,
+    validated: Arc<watch::Sender<bool>>,
+}
+
+impl FirmwareValidator {
+    pub fn new(config: &UpdateConfig, devpath: &str) -> Self {
+        let mut commands = Vec::new();
+        commands.extend(config.validate_command.clone());
+        if let Some(key) = &config.validate_uboot_key {
+            commands.push(format!("fw_setenv {} 1", key));
+        }
+        Self {
+            commands,
+            revert_command: config.revert_command(devpath),
+            timeout: config.validation_timeout_secs.map(Duration::from_secs),
+            validated: Arc::new(watch::channel(false).0),
+        }
+    }
+
+    /// How long new firmware has to validate before it is reverted.
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    /// Mark the running firmware as validated and stop the watchdog.
+    pub async fn validate(&self) -> Result<(), ValidationError> {
+        for command in &self.commands {
+            run_shell(command).await.map_err(ValidationError::Validate)?;
+        }
+        info!("firmware validated");
+        self.validated.send_replace(true);
+        Ok(())
+    }
+
+    /// Wait up to `timeout` for [`validate`](Self::validate). Returns
+    /// `false` if the firmware was validated in time.
+    pub async fn expired(&self, timeout: Duration) -> bool {
+        let mut validated = self.validated.subscribe();
+        let expired = tokio::time::timeout(timeout, validated.wait_for(|v| *v))
+            .await
+            .is_err();
+        expired
+    }
+
+    /// Switch back to the previous firmware.
+    pub async fn revert(&self) -> Result<(), ValidationError> {
+        warn!(command = %self.revert_command, "reverting firmware");
+        run_shell(&self.revert_command)
+            .await
+            .map_err(ValidationError::Revert)
+    }
+}
+
+/// Run `command` with `sh -c`; the error carries its stderr.
+async fn run_shell(command: &str) -> Result<(), String> {
+    let output = tokio::process::Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(std::process::Stdio::null())
+        .output()
+        .await
+        .map_err(|e| format!("failed to run {}: {}", command, e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "{} exit {}: {}",
+            command,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn validator(validate: &str, revert: &str, timeout_secs: Option<u64>) -> FirmwareValidator {
+        let config = UpdateConfig {
+            validate_command: Some(validate.to_string()),
+            revert_command: Some(revert.to_string()),
+            validation_timeout_secs: timeout_secs,
+            ..Default::default()
+        };
+        FirmwareValidator::new(&config, "/dev/mmcblk0")
+    }
+
+    #[tokio::test]
+    async fn validation_stops_watchdog() {
+        let dir = tempfile::tempdir().unwrap();
+        let reverted = dir.path().join("reverted");
+        let validator = validator("true", &format!("touch {}", reverted.display()), None);
+
+        let watchdog = validator.clone();
+        let handle = tokio::spawn(async move { watchdog.expired(Duration::from_secs(5)).await });
+        validator.validate().await.unwrap();
+        assert!(!handle.await.unwrap());
+        // Validated before the watchdog started
+        assert!(!validator.expired(Duration::ZERO).await);
+    }
+
+    #[tokio::test]
+    async fn reverts_without_validation() {
+        let dir = tempfile::tempdir().unwrap();
+        let reverted = dir.path().join("reverted");
+        let validator = validator("true", &format!("touch {}", reverted.display()), Some(1));
+        assert_eq!(validator.timeout(), Some(Duration::from_secs(1)));
+
+        assert!(validator.expired(Duration::from_millis(50)).await);
+        validator.revert().await.unwrap();
+        assert!(reverted.exists());
+    }
+
+    #[tokio::test]
+    async fn failed_validation_keeps_watchdog() {
+        let validator = validator("echo nope >&2; false", "true", None);
+        let err = validator.validate().await.unwrap_err();
+        assert!(err.to_string().contains("nope"));
+        assert!(validator.expired(Duration::from_millis(50)).await);
+    }
+
+    #[tokio::test]
+    async fn revert_failure() {
+        let validator = validator("true", "exit 3", None);
+        assert!(matches!(
+            validator.revert().await,
+            Err(ValidationError::Revert(_))
+        ));
+    }
+
+    #[test]
+    fn uboot_key_and_default_revert() {
+        let config = UpdateConfig {
+            validate_uboot_key: Some("nerves_fw_validated".to_string()),
+            ..Default::default()
+        };
+        let validator = FirmwareValidator::new(&config, "/dev/mmcblk1");
+        assert_eq!(validator.commands, vec!["fw_setenv nerves_fw_validated 1"]);
+        assert_eq!(
+            validator.revert_command,
+            "fwup -a -d /dev/mmcblk1 -i /usr/share/fwup/revert.fw -t revert && reboot"
+        );
+    }
+}